@@ -0,0 +1,30 @@
+use std::{env, fs, path::Path};
+
+// Records the version of every migration in `migrations/` so the service can
+// report which embedded migrations have been applied.
+fn main() {
+    println!("cargo:rerun-if-changed=migrations");
+
+    let mut versions: Vec<String> = fs::read_dir("migrations")
+        .expect("Could not read the migrations directory")
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.path().join("up.sql").is_file())
+        .filter_map(|entry| {
+            entry.file_name()
+                .to_string_lossy()
+                .split('_')
+                .next()
+                .map(|version| version.replace('-', ""))
+        })
+        .collect();
+
+    versions.sort();
+
+    let contents = format!(
+        "pub const MIGRATION_VERSIONS: &[&str] = &[{}];\n",
+        versions.iter().map(|version| format!("{:?}", version)).collect::<Vec<_>>().join(", ")
+    );
+
+    fs::write(Path::new(&env::var("OUT_DIR").unwrap()).join("migration_versions.rs"), contents)
+        .expect("Could not write migration versions");
+}
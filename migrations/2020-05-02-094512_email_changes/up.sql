@@ -0,0 +1,9 @@
+CREATE TABLE email_changes (
+  id UUID NOT NULL PRIMARY KEY,
+  revert_id UUID NOT NULL UNIQUE,
+  user_id UUID NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+  old_email VARCHAR(50) NOT NULL,
+  new_email VARCHAR(50) NOT NULL,
+  expires_at TIMESTAMP NOT NULL,
+  confirmed_at TIMESTAMP
+);
\ No newline at end of file
@@ -0,0 +1,6 @@
+CREATE TABLE account_deletions (
+  id UUID NOT NULL PRIMARY KEY,
+  user_id UUID NOT NULL UNIQUE REFERENCES users (id) ON DELETE CASCADE,
+  scheduled_for TIMESTAMP NOT NULL,
+  created_at TIMESTAMP NOT NULL
+);
\ No newline at end of file
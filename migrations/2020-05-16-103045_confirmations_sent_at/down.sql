@@ -0,0 +1 @@
+ALTER TABLE confirmations DROP COLUMN sent_at;
\ No newline at end of file
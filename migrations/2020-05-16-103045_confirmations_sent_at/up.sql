@@ -0,0 +1 @@
+ALTER TABLE confirmations ADD COLUMN sent_at TIMESTAMP NOT NULL DEFAULT NOW();
\ No newline at end of file
@@ -0,0 +1,3 @@
+ALTER TABLE users
+  DROP COLUMN roles,
+  DROP COLUMN disabled_at;
\ No newline at end of file
@@ -0,0 +1,3 @@
+ALTER TABLE users
+  ADD COLUMN roles TEXT[] NOT NULL DEFAULT '{}',
+  ADD COLUMN disabled_at TIMESTAMP;
\ No newline at end of file
@@ -0,0 +1,2 @@
+ALTER TABLE users
+  ADD COLUMN key_id VARCHAR(32) NOT NULL DEFAULT 'default';
\ No newline at end of file
@@ -0,0 +1,2 @@
+ALTER TABLE users
+  ALTER COLUMN hash TYPE VARCHAR(150);
\ No newline at end of file
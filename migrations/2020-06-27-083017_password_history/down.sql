@@ -0,0 +1,4 @@
+ALTER TABLE users
+  DROP COLUMN password_changed_at;
+
+DROP TABLE password_history;
\ No newline at end of file
@@ -0,0 +1,12 @@
+CREATE TABLE password_history (
+  id UUID NOT NULL PRIMARY KEY,
+  user_id UUID NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+  hash VARCHAR(255) NOT NULL,
+  key_id VARCHAR(32) NOT NULL,
+  created_at TIMESTAMP NOT NULL
+);
+
+CREATE INDEX password_history_user_id ON password_history (user_id, created_at);
+
+ALTER TABLE users
+  ADD COLUMN password_changed_at TIMESTAMP NOT NULL DEFAULT NOW();
\ No newline at end of file
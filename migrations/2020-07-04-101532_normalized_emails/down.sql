@@ -0,0 +1,9 @@
+DROP INDEX confirmations_normalized_email;
+
+ALTER TABLE confirmations
+  DROP COLUMN normalized_email;
+
+DROP INDEX users_normalized_email;
+
+ALTER TABLE users
+  DROP COLUMN normalized_email;
\ No newline at end of file
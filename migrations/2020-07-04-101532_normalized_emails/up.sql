@@ -0,0 +1,11 @@
+-- Filled in by `emails::backfill` once the migrations have run, so the normalized form only
+-- has one definition. NULLs don't collide, so the indexes can be built straight away.
+ALTER TABLE users
+  ADD COLUMN normalized_email VARCHAR(255);
+
+CREATE UNIQUE INDEX users_normalized_email ON users (normalized_email);
+
+ALTER TABLE confirmations
+  ADD COLUMN normalized_email VARCHAR(255);
+
+CREATE UNIQUE INDEX confirmations_normalized_email ON confirmations (normalized_email);
\ No newline at end of file
@@ -0,0 +1,3 @@
+ALTER TABLE users
+  DROP COLUMN username,
+  DROP COLUMN username_changed_at;
\ No newline at end of file
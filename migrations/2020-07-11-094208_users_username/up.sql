@@ -0,0 +1,3 @@
+ALTER TABLE users
+  ADD COLUMN username VARCHAR(32) UNIQUE,
+  ADD COLUMN username_changed_at TIMESTAMP;
\ No newline at end of file
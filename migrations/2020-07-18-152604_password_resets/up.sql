@@ -0,0 +1,8 @@
+CREATE TABLE password_resets (
+  id UUID NOT NULL PRIMARY KEY,
+  user_id UUID NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+  expires_at TIMESTAMP NOT NULL,
+  created_at TIMESTAMP NOT NULL
+);
+
+CREATE INDEX password_resets_user_id ON password_resets (user_id);
\ No newline at end of file
@@ -0,0 +1,328 @@
+use std::io::{Cursor, Write};
+
+use actix_web::{
+    http::header::CONTENT_DISPOSITION,
+    web,
+    HttpRequest,
+    HttpResponse
+};
+use actix_session::Session;
+use diesel::prelude::*;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use validator::Validate;
+use yarte::Template;
+
+use crate::{
+    credentials::CredentialPool,
+    db,
+    email_service::{send_account_deletion_mail, SharedMailer},
+    emails,
+    errors::AuthError,
+    models::{AccountDeletion, Confirmation, EmailChange, Pool, SessionUser, User},
+    schema::{account_deletions, confirmations, email_changes, password_history, password_resets, users},
+    templates::{DeleteAccount, Notice},
+    hashing::verify,
+    negotiation::{wants_json, Format},
+    utils::{get_current_user, to_sign_in},
+    validation::{Normalize, Validated},
+    vars
+};
+
+
+#[derive(Debug, Deserialize)]
+pub struct ExportQuery {
+    pub format: Option<String>,
+}
+
+#[derive(Deserialize, JsonSchema, Validate)]
+pub struct DeleteData {
+    #[validate(length(min = 1, max = 1024, message = "Enter your password"))]
+    pub password: String,
+}
+
+impl Normalize for DeleteData {}
+
+impl std::fmt::Debug for DeleteData {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DeleteData").field("password", &"[redacted]").finish()
+    }
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct Profile {
+    pub id: Uuid,
+    pub email: String,
+    pub username: Option<String>,
+    pub created_at: chrono::NaiveDateTime,
+    pub roles: Vec<String>,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct AccountExport {
+    pub exported_at: chrono::NaiveDateTime,
+    pub profile: Profile,
+    pub session: SessionUser,
+    pub email_changes: Vec<EmailChange>,
+    pub pending_confirmations: Vec<Confirmation>,
+    pub scheduled_deletion: Option<AccountDeletion>,
+}
+
+pub async fn export(session: Session,
+                    query: web::Query<ExportQuery>,
+                    pool: web::Data<Pool>) -> Result<HttpResponse, AuthError> {
+    let user = get_current_user(&session)?;
+    let data = db::run(move || collect_account_data(user, &pool)).await?;
+
+    match query.format.as_ref().map(String::as_str) {
+        Some("zip") => {
+            let archive = zip_account_data(&data)?;
+
+            Ok(HttpResponse::Ok()
+                .content_type("application/zip")
+                .header(CONTENT_DISPOSITION, "attachment; filename=\"account.zip\"")
+                .body(archive))
+        },
+        _ => {
+            Ok(HttpResponse::Ok()
+                .header(CONTENT_DISPOSITION, "attachment; filename=\"account.json\"")
+                .json(data))
+        },
+    }
+}
+
+pub async fn show_delete_form(session: Session) -> HttpResponse {
+    match get_current_user(&session) {
+        Ok(user) => {
+            let t = DeleteAccount { email: user.email, error: None };
+
+            HttpResponse::Ok().content_type("text/html; charset=utf-8").body(t.call().unwrap())
+        },
+        Err(_) => to_sign_in("/me/delete"),
+    }
+}
+
+pub async fn delete_account(session: Session,
+                            data: Validated<DeleteData>,
+                            pool: web::Data<Pool>,
+                            mailer: web::Data<SharedMailer>,
+                            credentials: web::Data<CredentialPool>) -> Result<HttpResponse, AuthError> {
+    let user = get_current_user(&session)?;
+    let password = data.into_inner()?.password;
+    let deletion = credentials
+        .run(move || schedule_deletion(&user, &password, &pool, &mailer))
+        .await?;
+
+    session.clear();
+
+    match deletion {
+        Some(deletion) => Ok(HttpResponse::Accepted().json(deletion)),
+        None => Ok(HttpResponse::NoContent().finish()),
+    }
+}
+
+pub async fn delete_account_for_browser(session: Session,
+                                        data: Validated<DeleteData>,
+                                        pool: web::Data<Pool>,
+                                        mailer: web::Data<SharedMailer>,
+                                        credentials: web::Data<CredentialPool>) -> Result<HttpResponse, AuthError> {
+    let user = match get_current_user(&session) {
+        Ok(user) => user,
+        Err(_) => return Ok(to_sign_in("/me/delete")),
+    };
+    let current_email = user.email.clone();
+    let result = match data.into_inner() {
+        Ok(data) => credentials.run(move || schedule_deletion(&user, &data.password, &pool, &mailer)).await,
+        Err(err) => Err(err),
+    };
+
+    match result {
+        Ok(deletion) => {
+            session.clear();
+
+            let message = match deletion {
+                Some(_) => "Check your mailbox for a link to cancel the deletion",
+                None => "All your data has been removed",
+            };
+            let t = Notice {
+                title: String::from("Your account is being deleted"),
+                message: String::from(message),
+                success: true
+            };
+
+            Ok(HttpResponse::Ok().content_type("text/html; charset=utf-8").body(t.call().unwrap()))
+        },
+        Err(err) => {
+            let t = DeleteAccount { email: current_email, error: Some(err.user_message()) };
+
+            Ok(HttpResponse::Ok().content_type("text/html; charset=utf-8").body(t.call().unwrap()))
+        },
+    }
+}
+
+pub async fn cancel_deletion(path_id: web::Path<String>,
+                             req: HttpRequest,
+                             pool: web::Data<Pool>) -> Result<HttpResponse, AuthError> {
+    let result = db::run(move || remove_deletion(&path_id.into_inner(), &pool)).await;
+    let is_json = wants_json(&req, Format::Html);
+
+    match result {
+        Ok(_) => {
+            if is_json {
+                Ok(HttpResponse::NoContent().finish())
+            } else {
+                let t = Notice {
+                    title: String::from("Your account will not be deleted"),
+                    message: String::from("The deletion has been cancelled"),
+                    success: true
+                };
+
+                Ok(HttpResponse::Ok().content_type("text/html; charset=utf-8").body(t.call().unwrap()))
+            }
+        },
+        Err(auth_error) => {
+            if is_json {
+                Err(auth_error)
+            } else {
+                let t = Notice {
+                    title: String::from("Deletion not cancelled"),
+                    message: auth_error.user_message(),
+                    success: false
+                };
+
+                Ok(HttpResponse::Ok().content_type("text/html; charset=utf-8").body(t.call().unwrap()))
+            }
+        },
+    }
+}
+
+// Removes every account whose grace period is over. Returns the number of accounts removed.
+pub fn remove_due_accounts(conn: &PgConnection) -> Result<usize, AuthError> {
+    let due = account_deletions::table
+        .filter(account_deletions::scheduled_for.le(chrono::Local::now().naive_local()))
+        .load::<AccountDeletion>(conn)?;
+
+    for deletion in &due {
+        remove_account(deletion.user_id, conn)?;
+    }
+
+    Ok(due.len())
+}
+
+pub fn remove_account(user_id: Uuid, conn: &PgConnection) -> Result<(), AuthError> {
+    conn.transaction(|| {
+        let record = users::table.find(user_id).get_result::<User>(conn)?;
+
+        diesel::delete(confirmations::table.filter(confirmations::normalized_email.eq(&record.normalized_email))).execute(conn)?;
+        diesel::delete(email_changes::table.filter(email_changes::user_id.eq(user_id))).execute(conn)?;
+        diesel::delete(account_deletions::table.filter(account_deletions::user_id.eq(user_id))).execute(conn)?;
+        diesel::delete(password_history::table.filter(password_history::user_id.eq(user_id))).execute(conn)?;
+        diesel::delete(password_resets::table.filter(password_resets::user_id.eq(user_id))).execute(conn)?;
+
+        if vars::account_deletion_mode() == "anonymize" {
+            // keep the row for referential purposes but drop anything that identifies the person
+            let email = format!("deleted-{}@invalid", user_id.to_simple());
+
+            diesel::update(users::table.find(user_id))
+                .set((
+                    users::normalized_email.eq(emails::normalize(&email)),
+                    users::email.eq(email),
+                    users::hash.eq(""),
+                    users::username.eq(None::<String>),
+                    users::username_changed_at.eq(None::<chrono::NaiveDateTime>),
+                    users::roles.eq(Vec::<String>::new()),
+                    // never signs in again, whatever the hash
+                    users::disabled_at.eq(Some(chrono::Local::now().naive_local()))
+                ))
+                .execute(conn)?;
+        } else {
+            diesel::delete(users::table.find(user_id)).execute(conn)?;
+        }
+
+        Ok(())
+    })
+}
+
+
+fn collect_account_data(user: SessionUser, pool: &web::Data<Pool>) -> Result<AccountExport, AuthError> {
+    let conn = &db::get(pool)?;
+    let record = users::table.find(user.id).get_result::<User>(conn)?;
+
+    Ok(AccountExport {
+        exported_at: chrono::Local::now().naive_local(),
+        email_changes: email_changes::table
+            .filter(email_changes::user_id.eq(record.id))
+            .load::<EmailChange>(conn)?,
+        pending_confirmations: confirmations::table
+            .filter(confirmations::normalized_email.eq(&record.normalized_email))
+            .load::<Confirmation>(conn)?,
+        scheduled_deletion: account_deletions::table
+            .filter(account_deletions::user_id.eq(record.id))
+            .first::<AccountDeletion>(conn)
+            .optional()?,
+        profile: Profile {
+            id: record.id,
+            email: record.email,
+            username: record.username,
+            created_at: record.created_at,
+            roles: record.roles
+        },
+        session: user,
+    })
+}
+
+fn zip_account_data(data: &AccountExport) -> Result<Vec<u8>, AuthError> {
+    let archive_error = || AuthError::ProcessError(String::from("Could not create archive"));
+    let json = serde_json::to_vec_pretty(data).map_err(|_| archive_error())?;
+    let mut writer = zip::ZipWriter::new(Cursor::new(Vec::new()));
+
+    writer.start_file("account.json", zip::write::FileOptions::default()).map_err(|_| archive_error())?;
+    writer.write_all(&json).map_err(|_| archive_error())?;
+
+    Ok(writer.finish().map_err(|_| archive_error())?.into_inner())
+}
+
+// Returns the scheduled deletion, or None when the account was removed right away
+fn schedule_deletion(user: &SessionUser,
+                     password: &str,
+                     pool: &web::Data<Pool>,
+                     mailer: &web::Data<SharedMailer>) -> Result<Option<AccountDeletion>, AuthError> {
+    let conn = &db::get(pool)?;
+    let record = users::table.find(user.id).get_result::<User>(conn)?;
+
+    if !verify(&record.hash, &record.key_id, password).unwrap_or(false) {
+        return Err(AuthError::AuthenticationError(String::from("Invalid password")));
+    }
+
+    let grace_hours = vars::account_deletion_grace_hours();
+
+    if grace_hours <= 0 {
+        remove_account(record.id, conn)?;
+
+        return Ok(None);
+    }
+
+    let deletion: AccountDeletion = diesel::insert_into(account_deletions::table)
+                                        .values(&AccountDeletion::from(user, grace_hours))
+                                        .on_conflict(account_deletions::user_id)
+                                        .do_nothing()
+                                        .get_result(conn)
+                                        .optional()?
+                                        .ok_or_else(|| AuthError::DuplicateValue(String::from("Account is already scheduled for deletion")))?;
+
+    send_account_deletion_mail(mailer.get_ref().as_ref(), &record.email, &deletion)?;
+
+    Ok(Some(deletion))
+}
+
+fn remove_deletion(path_id: &str, pool: &web::Data<Pool>) -> Result<(), AuthError> {
+    let path_uuid = Uuid::parse_str(path_id)?;
+    let removed = diesel::delete(account_deletions::table.find(path_uuid)).execute(&db::get(pool)?)?;
+
+    if removed == 0 {
+        Err(AuthError::NotFound(String::from("Scheduled deletion not found")))
+    } else {
+        Ok(())
+    }
+}
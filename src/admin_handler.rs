@@ -0,0 +1,63 @@
+use actix_web::{web, HttpRequest, HttpResponse};
+use diesel::prelude::*;
+
+use crate::{db, emails, errors::AuthError, hashing, migrations, schema::users, models::Pool, scheduler::Scheduler, utils::authorize_bearer, vars};
+
+
+pub async fn maintenance_status(req: HttpRequest, scheduler: web::Data<Scheduler>) -> Result<HttpResponse, AuthError> {
+    authorize(&req)?;
+
+    Ok(HttpResponse::Ok().json(scheduler.status()))
+}
+
+pub async fn run_maintenance(req: HttpRequest, scheduler: web::Data<Scheduler>) -> Result<HttpResponse, AuthError> {
+    authorize(&req)?;
+
+    let report = scheduler.run_now().await?;
+
+    Ok(HttpResponse::Ok().json(report))
+}
+
+pub async fn migration_status(req: HttpRequest, pool: web::Data<Pool>) -> Result<HttpResponse, AuthError> {
+    authorize(&req)?;
+
+    let status = db::run(move || migrations::status(&db::get(&pool)?)).await?;
+
+    Ok(HttpResponse::Ok().json(status))
+}
+
+// How many users still have hashes made with weaker parameters or a retired key
+pub async fn hash_report(req: HttpRequest, pool: web::Data<Pool>) -> Result<HttpResponse, AuthError> {
+    authorize(&req)?;
+
+    let hashes = db::run(move || {
+        let hashes = users::table.select((users::hash, users::key_id)).load::<(String, String)>(&db::get(&pool)?)?;
+
+        Ok(hashes)
+    })
+    .await?;
+
+    Ok(HttpResponse::Ok().json(hashing::report(hashes.iter().map(|(hash, key_id)| (hash.as_str(), key_id.as_str())))))
+}
+
+// Users whose addresses are one account under the EMAIL_* rules, see `auth-admin email-collisions`
+pub async fn email_report(req: HttpRequest, pool: web::Data<Pool>) -> Result<HttpResponse, AuthError> {
+    authorize(&req)?;
+
+    let records = db::run(move || {
+        let records = users::table.select((users::id, users::email)).load::<(uuid::Uuid, String)>(&db::get(&pool)?)?;
+
+        Ok(records)
+    })
+    .await?;
+
+    Ok(HttpResponse::Ok().json(emails::report(records.iter().map(|(id, email)| (*id, email.as_str())))))
+}
+
+
+// Admin requests carry `Authorization: Bearer <ADMIN_TOKEN>`
+fn authorize(req: &HttpRequest) -> Result<(), AuthError> {
+    let token = vars::admin_token().ok_or_else(|| AuthError::AuthenticationError(String::from("Unauthorized")))?;
+
+    authorize_bearer(req, &token)
+}
@@ -1,39 +1,63 @@
 
 use actix_session::Session;
 use actix_web::{
-    http::header::LOCATION,
+    http::header::{HeaderMap, LOCATION},
     HttpRequest,
     HttpResponse,
     web
 };
 use diesel::prelude::*;
+use schemars::JsonSchema;
 use serde::Deserialize;
+use tracing::{debug, info, warn};
+use validator::Validate;
 use yarte::Template;
 
 use crate::{
+    credentials::CredentialPool,
+    db,
+    emails,
     models::{Pool, SessionUser, User},
     errors::AuthError,
-    utils::{is_json_request, get_current_user, is_signed_in, set_current_user, to_home, verify}, 
-    templates::{SignIn, Me}
+    hashing::{hash_password, needs_rehash, verify, verify_dummy},
+    metrics,
+    negotiation::{negotiate, wants_json, Format},
+    password_history,
+    telemetry::redact_email,
+    utils::{
+        get_current_user,
+        is_signed_in,
+        redirect_to,
+        remember_return_to,
+        safe_return_to,
+        set_current_user,
+        set_password_change_user,
+        to_return_to_or_home,
+        to_sign_in
+    },
+    templates::{SignIn, Me},
+    usernames,
+    validation::{normalize_text, Normalize, Validated},
+    vars
 };
 
 
-pub async fn me(session: Session, req: HttpRequest) -> HttpResponse {
-    let user_result = dbg!(get_current_user(&session));
+pub async fn me(session: Session, req: HttpRequest) -> Result<HttpResponse, AuthError> {
+    let user_result = get_current_user(&session);
 
-    match is_json_request(&req) {
-        true => {
-            user_result.map_or(HttpResponse::Unauthorized().json("Unauthorized"), |user| HttpResponse::Ok().json(user))
-        },
-        false => {
-            user_result.map_or(
-                HttpResponse::MovedPermanently().header(LOCATION, "/signin").finish(), 
+    debug!(user_id = ?user_result.as_ref().ok().map(|user| user.id), "Current user");
+
+    match negotiate(&req, Format::Html) {
+        Format::Json => user_result.map(|user| HttpResponse::Ok().json(user)),
+        Format::Html => {
+            Ok(user_result.map_or(
+                to_sign_in("/me"),
                 |user| {
                     let t = Me { user };
             
                     HttpResponse::Ok().content_type("text/html; charset=utf-8").body(t.call().unwrap())
                 }
-            )
+            ))
         }
     }
 }
@@ -41,72 +65,173 @@ pub async fn me(session: Session, req: HttpRequest) -> HttpResponse {
 pub async fn sign_out(session: Session, req: HttpRequest) -> HttpResponse {
     session.clear();
     
-    match is_json_request(&req) {
-        true => HttpResponse::NoContent().finish(),
-        false => HttpResponse::MovedPermanently().header(LOCATION, "/signin").finish(),
+    match negotiate(&req, Format::Html) {
+        Format::Json => HttpResponse::NoContent().finish(),
+        Format::Html => HttpResponse::MovedPermanently().header(LOCATION, "/signin").finish(),
     }
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Deserialize, JsonSchema, Validate)]
 pub struct AuthData {
-    pub email: String,
+    // An email address or a username, which can't contain "@"
+    #[serde(alias = "email", alias = "username")]
+    #[validate(length(min = 1, max = 50, message = "Enter your email address or username"))]
+    pub login: String,
+    #[validate(length(min = 1, max = 1024, message = "Enter your password"))]
     pub password: String,
+    #[serde(default, alias = "next")]
+    pub return_to: Option<String>,
+}
+
+// Keep credentials out of logs
+impl std::fmt::Debug for AuthData {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AuthData")
+            .field("login", &redact_email(&self.login))
+            .field("password", &"[redacted]")
+            .field("return_to", &self.return_to)
+            .finish()
+    }
+}
+
+impl Normalize for AuthData {
+    fn normalize(&mut self) {
+        self.login = normalize_text(&self.login);
+        self.return_to = self.return_to.as_deref().map(str::trim).filter(|url| !url.is_empty()).map(String::from);
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ReturnTo {
+    #[serde(alias = "next")]
+    pub return_to: Option<String>,
 }
 
-pub async fn sign_in(data: web::Json<AuthData>, 
+#[derive(Debug, Deserialize)]
+pub struct VerifyQuery {
+    // Traefik and Envoy pass our response on to the client, so they can use a redirect.
+    // nginx's auth_request only understands 2xx, 401 and 403.
+    #[serde(default)]
+    pub redirect: bool,
+    pub role: Option<String>,
+}
+
+// Serves both API clients and the sign-in form, see `negotiation::negotiate`
+pub async fn sign_in(data: Validated<AuthData>, 
                   session: Session, 
                   req: HttpRequest,
-                  pool: web::Data<Pool>) -> Result<HttpResponse, AuthError> {
+                  pool: web::Data<Pool>,
+                  credentials: web::Data<CredentialPool>) -> Result<HttpResponse, AuthError> {
     match is_signed_in(&session) {
         true => {
-            let response = get_current_user(&session).map(|user| HttpResponse::Ok().json(user)).unwrap();
+            match negotiate(&req, Format::Html) {
+                Format::Json => get_current_user(&session).map(|user| HttpResponse::Ok().json(user)),
+                Format::Html => {
+                    let return_to = data
+                        .into_inner()
+                        .ok()
+                        .and_then(|data| data.return_to)
+                        .and_then(|url| safe_return_to(&url));
 
-            Ok(response)
+                    Ok(to_return_to_or_home(&session, return_to.as_deref()))
+                },
+            }
         },
-        false => handle_sign_in(data.into_inner(), &session, &req, &pool)
+        false => {
+            match (data.into_parts(), negotiate(&req, Format::Html)) {
+                ((data, Ok(_)), _) => handle_sign_in(data, &session, &req, pool, &credentials).await,
+                ((_, Err(err)), Format::Json) => Err(err),
+                // shown again with what was typed, apart from the password
+                ((data, Err(err)), Format::Html) => {
+                    let t = SignIn {
+                        error: None,
+                        login: data.login,
+                        return_to: data.return_to.and_then(|url| safe_return_to(&url)),
+                        login_errors: err.field_messages("login"),
+                        password_errors: err.field_messages("password")
+                    };
+
+                    Ok(HttpResponse::Ok().content_type("text/html; charset=utf-8").body(t.call().unwrap()))
+                },
+            }
+        }
     }
 }
 
-pub async fn show_sign_in_form(session: Session) -> Result<HttpResponse, AuthError> {
+pub async fn show_sign_in_form(session: Session, query: web::Query<ReturnTo>) -> Result<HttpResponse, AuthError> {
+    let return_to = query.into_inner().return_to.and_then(|url| safe_return_to(&url));
+
     match is_signed_in(&session) {
-        true => Ok(to_home()),
+        true => Ok(to_return_to_or_home(&session, return_to.as_deref())),
         false => {
-            let t = SignIn { error: None };
+            if let Some(url) = &return_to {
+                remember_return_to(&session, url);
+            }
+
+            let t = SignIn { error: None, login: String::new(), return_to, login_errors: vec![], password_errors: vec![] };
 
             Ok(HttpResponse::Ok().content_type("text/html; charset=utf-8").body(t.call().unwrap()))
         }
     }
 }
 
-pub async fn sign_in_for_browser(data: web::Form<AuthData>, 
-                                session: Session, 
-                                req: HttpRequest,
-                                pool: web::Data<Pool>) -> Result<HttpResponse, AuthError> {
-    handle_sign_in(data.into_inner(), &session, &req, &pool)
-}
+async fn handle_sign_in(data: AuthData, 
+                      session: &Session, 
+                      req: &HttpRequest,
+                      pool: web::Data<Pool>,
+                      credentials: &CredentialPool) -> Result<HttpResponse, AuthError> {
+    let return_to = data.return_to.as_ref().and_then(|url| safe_return_to(url));
+    let login = redact_email(&data.login);
+    let typed_login = data.login.clone();
+    let result = credentials.run(move || find_user(data, &pool)).await;
+
+    // a full queue isn't a failed sign-in, let the client retry
+    if let Err(AuthError::TooManyRequests(message)) = result {
+        return Err(AuthError::TooManyRequests(message));
+    }
+
+    let is_json = wants_json(req, Format::Html);
 
-fn handle_sign_in(data: AuthData, 
-                session: &Session, 
-                req: &HttpRequest,
-                pool: &web::Data<Pool>) -> Result<HttpResponse, AuthError> {
-    let result = find_user(data, pool);
-    let is_json = is_json_request(req);
+    metrics::SIGN_INS.with_label_values(&[if result.is_ok() { "success" } else { "failure" }]).inc();
+    info!(login = %login, success = result.is_ok(), "Sign-in attempt");
 
     match result {
+        // no session until the password has been changed
+        Ok(user) if password_history::is_expired(&user) => {
+            set_password_change_user(&session, &user.into());
+
+            if let Some(url) = &return_to {
+                remember_return_to(&session, url);
+            }
+
+            if is_json {
+                Err(AuthError::PasswordExpired(String::from("/me/password")))
+            } else {
+                Ok(redirect_to("/me/password"))
+            }
+        },
         Ok(user) => {
+            let user: SessionUser = user.into();
+
             set_current_user(&session, &user);
 
             if is_json {
                 Ok(HttpResponse::Ok().json(user))
             } else {
-                Ok(to_home())
+                Ok(to_return_to_or_home(&session, return_to.as_deref()))
             }
         },
         Err(err) => {
             if is_json {
-                Ok(HttpResponse::Unauthorized().json(err.to_string()))
+                Err(err)
             } else {
-                let t = SignIn { error: Some(err.to_string()) };
+                let t = SignIn {
+                    error: Some(err.user_message()),
+                    login: typed_login,
+                    return_to,
+                    login_errors: vec![],
+                    password_errors: vec![]
+                };
     
                 Ok(HttpResponse::Ok().content_type("text/html; charset=utf-8").body(t.call().unwrap()))
             }
@@ -114,20 +239,111 @@ fn handle_sign_in(data: AuthData,
     }
 }
 
-fn find_user(data: AuthData, pool: &web::Data<Pool>) -> Result<SessionUser, AuthError> {
-    use crate::schema::users::dsl::{email, users};
+// Forward-auth check for reverse proxies (nginx auth_request, Traefik forwardAuth, Envoy ext_authz).
+// Only the session cookie counts, which `sessions::SessionCheck` has checked against the database.
+pub async fn verify_request(session: Session,
+                            req: HttpRequest,
+                            query: web::Query<VerifyQuery>) -> Result<HttpResponse, AuthError> {
+    let query = query.into_inner();
+
+    match get_current_user(&session) {
+        Ok(user) => {
+            if let Some(role) = query.role {
+                if !user.roles.contains(&role) {
+                    return Ok(HttpResponse::Forbidden().finish());
+                }
+            }
+
+            let mut res = HttpResponse::Ok();
+
+            res.header("X-Auth-User-Id", user.id.to_string())
+                .header("X-Auth-User-Email", user.email.as_str())
+                .header("X-Auth-User-Roles", user.roles.join(","));
+
+            if let Some(username) = &user.username {
+                res.header("X-Auth-User-Name", username.as_str());
+            }
+
+            Ok(res.finish())
+        },
+        Err(_) => {
+            if query.redirect {
+                let location = original_url(req.headers())
+                    .and_then(|url| safe_return_to(&url))
+                    .map_or_else(
+                        || format!("{}/signin", vars::domain_url()),
+                        |url| format!(
+                            "{}/signin?return_to={}",
+                            vars::domain_url(),
+                            url::form_urlencoded::byte_serialize(url.as_bytes()).collect::<String>()
+                        )
+                    );
+
+                Ok(redirect_to(&location))
+            } else {
+                Ok(HttpResponse::Unauthorized().finish())
+            }
+        },
+    }
+}
+
+
+// The URL the proxy was asked for, from the headers the common proxies set. A client can set
+// them as well, so the result is only used through `safe_return_to`.
+fn original_url(headers: &HeaderMap) -> Option<String> {
+    let header = |name: &str| headers.get(name).and_then(|value| value.to_str().ok());
+
+    if let Some(url) = header("X-Original-URL") {
+        return Some(String::from(url));
+    }
+
+    let host = header("X-Forwarded-Host")?;
+    let proto = header("X-Forwarded-Proto").unwrap_or("https");
+    let uri = header("X-Forwarded-Uri").or_else(|| header("X-Original-URI")).unwrap_or("/");
+
+    Some(format!("{}://{}{}", proto, host, uri))
+}
+
+fn find_user(data: AuthData, pool: &web::Data<Pool>) -> Result<User, AuthError> {
+    use crate::schema::users::dsl::{normalized_email, username, users};
     
-    let mut items = users
-        .filter(email.eq(&data.email))
-        .load::<User>(&pool.get().unwrap())?;
-
-    if let Some(user) = items.pop() {
-        if let Ok(matching) = verify(&user.hash, &data.password) {
-            if matching {
-                return Ok(user.into());
+    let conn = &db::get(pool)?;
+    let mut items = if data.login.contains('@') {
+        users.filter(normalized_email.eq(emails::normalize(&data.login))).load::<User>(conn)?
+    } else {
+        users.filter(username.eq(usernames::normalize(&data.login))).load::<User>(conn)?
+    };
+
+    match items.pop() {
+        Some(user) => {
+            if verify(&user.hash, &user.key_id, &data.password).unwrap_or(false) && user.disabled_at.is_none() {
+                if needs_rehash(&user.hash, &user.key_id) {
+                    rehash(&user, &data.password, conn);
+                }
+
+                return Ok(user);
             }
-        }
+        },
+        // as slow as checking a real password
+        None => verify_dummy(&data.password),
     }
 
-    Err(AuthError::NotFound(String::from("User not found")))
+    // the same for unknown accounts, wrong passwords and disabled accounts, so none can be told apart
+    Err(AuthError::AuthenticationError(String::from("Incorrect login or password")))
+}
+
+// Brings the stored hash up to the current parameters and key. Sign-in goes ahead even if this fails.
+fn rehash(user: &User, password: &str, conn: &PgConnection) {
+    use crate::schema::users::dsl::{hash, key_id, users};
+
+    let result = hash_password(password).and_then(|new_hash| {
+        diesel::update(users.find(user.id))
+            .set((hash.eq(new_hash.hash), key_id.eq(new_hash.key_id)))
+            .execute(conn)
+            .map_err(AuthError::from)
+    });
+
+    if let Err(err) = result {
+        warn!(user_id = %user.id, "Could not rehash password: {}", err);
+    }
 }
@@ -0,0 +1,399 @@
+use std::{
+    fs::File,
+    io::{BufRead, BufReader},
+    path::PathBuf
+};
+
+use diesel::{pg::PgConnection, prelude::*};
+use rand::Rng;
+use serde::Deserialize;
+use structopt::StructOpt;
+
+use auth_service::{
+    account_handler::remove_account,
+    db,
+    emails,
+    errors::AuthError,
+    migrations,
+    models::{Confirmation, User},
+    password_history,
+    password_policy,
+    register_handler::RegisterData,
+    schema::{confirmations, users},
+    hash_formats::HashFormat,
+    hashing::{self, hash_password, PasswordHash},
+    usernames,
+    validation,
+    vars
+};
+
+
+#[derive(Debug, StructOpt)]
+#[structopt(name = "auth-admin", about = "Operational tasks for the auth service")]
+enum Command {
+    /// Run pending embedded database migrations
+    Migrate,
+    /// Create a user, prompting for the password
+    CreateUser {
+        email: String,
+        /// Role to give the user, may be repeated
+        #[structopt(long = "role")]
+        roles: Vec<String>,
+        /// Username to sign in with besides the email
+        #[structopt(long)]
+        username: Option<String>,
+    },
+    /// Prevent a user from signing in
+    DisableUser { email: String },
+    /// Allow a disabled user to sign in again
+    EnableUser { email: String },
+    /// Remove a user and everything held about them
+    DeleteUser { email: String },
+    /// Replace a user's password, prompting for the new one
+    SetPassword { email: String },
+    /// Give a user a role
+    AssignRole { email: String, role: String },
+    /// Take a role away from a user
+    RevokeRole { email: String, role: String },
+    /// List pending registration confirmations
+    ListConfirmations,
+    /// Remove expired registration confirmations
+    PurgeConfirmations {
+        /// Remove pending confirmations too
+        #[structopt(long)]
+        all: bool,
+    },
+    /// Import users with their existing bcrypt, PBKDF2-SHA256, scrypt or Firebase-scrypt hashes.
+    /// Argon2 hashes are refused, they can only be verified with the secret key they were made with.
+    /// CSV files have the columns email,hash,roles with roles separated by ';'.
+    /// JSONL files have one {"email", "hash", "roles"} object per line.
+    ImportUsers {
+        path: PathBuf,
+        /// csv or jsonl, guessed from the file extension when not given
+        #[structopt(long)]
+        format: Option<String>,
+    },
+    /// Count users whose password hashes use weaker Argon2 parameters or a retired key
+    HashReport,
+    /// List users whose emails are the same address under the EMAIL_* rules. Works before
+    /// the normalized email migration, which fails until they're resolved.
+    EmailCollisions,
+    /// Bring stored normalized emails up to the EMAIL_* rules, run after changing them
+    NormalizeEmails,
+    /// Print freshly generated SESSION_KEY and SECRET_KEY values
+    GenerateKeys,
+}
+
+fn main() {
+    let command = Command::from_args();
+
+    if let Err(err) = run(command) {
+        eprintln!("{}", err);
+        std::process::exit(1);
+    }
+}
+
+
+fn run(command: Command) -> Result<(), AuthError> {
+    if let Command::GenerateKeys = command {
+        return generate_keys();
+    }
+
+    hashing::check_keys()?;
+
+    let conn = db::connect_unpooled(&vars::database_url())?;
+
+    match command {
+        Command::Migrate => {
+            migrations::run(&conn)?;
+
+            for migration in migrations::status(&conn)? {
+                println!("{}\t{}", migration.version, if migration.applied { "applied" } else { "pending" });
+            }
+        },
+        Command::CreateUser { email, roles, username } => {
+            let email = validation::validate(RegisterData { email })?.email;
+            let username = username.as_deref().map(usernames::normalize);
+
+            if let Some(username) = &username {
+                usernames::check(username)?;
+                usernames::ensure_available(username, None, &conn)?;
+            }
+
+            let password = prompt_password()?;
+
+            password_policy::check(&password, &email)?;
+
+            let mut user = User::from(email, hash_password(&password)?);
+            user.roles = roles;
+            user.username = username;
+
+            let user: User = diesel::insert_into(users::table).values(&user).get_result(&conn)?;
+
+            println!("Created user {} ({})", user.email, user.id);
+        },
+        Command::DisableUser { email } => {
+            let user = find_user(&email, &conn)?;
+
+            diesel::update(users::table.find(user.id))
+                .set(users::disabled_at.eq(Some(chrono::Local::now().naive_local())))
+                .execute(&conn)?;
+
+            println!("Disabled {}", email);
+        },
+        Command::EnableUser { email } => {
+            let user = find_user(&email, &conn)?;
+
+            diesel::update(users::table.find(user.id))
+                .set(users::disabled_at.eq(None::<chrono::NaiveDateTime>))
+                .execute(&conn)?;
+
+            println!("Enabled {}", email);
+        },
+        Command::DeleteUser { email } => {
+            let user = find_user(&email, &conn)?;
+
+            remove_account(user.id, &conn)?;
+
+            println!("Deleted {}", email);
+        },
+        Command::SetPassword { email } => {
+            let user = find_user(&email, &conn)?;
+            let password = prompt_password()?;
+
+            password_policy::check(&password, &user.email)?;
+            password_history::check_reuse(&user, &password, &conn)?;
+            password_history::change(&user, hash_password(&password)?, &conn)?;
+
+            println!("Password updated for {}", email);
+        },
+        Command::AssignRole { email, role } => {
+            let user = find_user(&email, &conn)?;
+            let mut roles = user.roles;
+
+            if !roles.contains(&role) {
+                roles.push(role);
+            }
+
+            update_roles(user.id, roles, &conn)?;
+        },
+        Command::RevokeRole { email, role } => {
+            let user = find_user(&email, &conn)?;
+            let roles = user.roles.into_iter().filter(|r| r != &role).collect();
+
+            update_roles(user.id, roles, &conn)?;
+        },
+        Command::ListConfirmations => {
+            let records = confirmations::table
+                .order(confirmations::sent_at.desc())
+                .load::<Confirmation>(&conn)?;
+
+            for record in records {
+                println!("{}\t{}\texpires {}", record.id, record.email, record.expires_at);
+            }
+        },
+        Command::PurgeConfirmations { all } => {
+            let removed = if all {
+                diesel::delete(confirmations::table).execute(&conn)?
+            } else {
+                diesel::delete(confirmations::table.filter(confirmations::expires_at.lt(chrono::Local::now().naive_local())))
+                    .execute(&conn)?
+            };
+
+            println!("Removed {} confirmation(s)", removed);
+        },
+        Command::ImportUsers { path, format } => {
+            let format = format
+                .or_else(|| path.extension().map(|extension| extension.to_string_lossy().to_lowercase()))
+                .unwrap_or_default();
+            let records = match format.as_str() {
+                "csv" => read_csv(&path)?,
+                "jsonl" | "ndjson" => read_jsonl(&path)?,
+                _ => return Err(AuthError::GenericError(String::from("Use --format csv or --format jsonl"))),
+            };
+            let (mut imported, mut existing, mut invalid) = (0, 0, 0);
+
+            for (line, record) in records.into_iter().enumerate() {
+                // Argon2 hashes from elsewhere weren't made with any of our SECRET_KEYS
+                let importable = HashFormat::detect(&record.hash).map_or(false, |format| format != HashFormat::Argon2);
+                let email = match validation::validate(RegisterData { email: record.email }) {
+                    Ok(RegisterData { email }) if importable => email,
+                    _ => {
+                        eprintln!("Record {}: invalid email or unsupported hash, skipped", line + 1);
+                        invalid += 1;
+                        continue;
+                    },
+                };
+
+                // rehashed to Argon2 with the current key on first sign-in
+                let mut user = User::from(email, PasswordHash { hash: record.hash, key_id: String::from("imported") });
+                user.roles = record.roles;
+
+                let inserted = diesel::insert_into(users::table)
+                    .values(&user)
+                    .on_conflict(users::normalized_email)
+                    .do_nothing()
+                    .execute(&conn)?;
+
+                if inserted == 0 {
+                    existing += 1;
+                } else {
+                    imported += 1;
+                }
+            }
+
+            println!("Imported {} user(s), skipped {} existing and {} invalid", imported, existing, invalid);
+        },
+        Command::HashReport => {
+            let hashes = users::table.select((users::hash, users::key_id)).load::<(String, String)>(&conn)?;
+            let report = hashing::report(hashes.iter().map(|(hash, key_id)| (hash.as_str(), key_id.as_str())));
+
+            println!("Current parameters: {}, key {}", report.current, report.current_key_id);
+            println!("{} of {} user(s) on older parameters or a retired key", report.outdated, report.total);
+
+            for (params, count) in report.by_params {
+                println!("{}\t{}", params, count);
+            }
+
+            for (key_id, count) in report.by_key_id {
+                println!("key {}\t{}", key_id, count);
+            }
+        },
+        Command::EmailCollisions => {
+            let records = users::table.select((users::id, users::email)).load::<(uuid::Uuid, String)>(&conn)?;
+            let report = emails::report(records.iter().map(|(id, email)| (*id, email.as_str())));
+
+            println!("{} address(es) shared among {} user(s)", report.collisions.len(), report.total);
+
+            for (normalized, colliding) in report.collisions {
+                println!("{}", normalized);
+
+                for user in colliding {
+                    println!("\t{}\t{}", user.id, user.email);
+                }
+            }
+        },
+        Command::NormalizeEmails => {
+            let records = users::table
+                .select((users::id, users::email, users::normalized_email))
+                .load::<(uuid::Uuid, String, String)>(&conn)?;
+            let report = emails::report(records.iter().map(|(id, email, _)| (*id, email.as_str())));
+            let (mut updated, mut skipped) = (0, 0);
+
+            for (id, email, stored) in &records {
+                let normalized = emails::normalize(email);
+
+                if &normalized == stored {
+                    continue;
+                }
+
+                // left as they are until `email-collisions` is resolved
+                if report.collisions.contains_key(&normalized) {
+                    eprintln!("{} collides with another user, skipped", email);
+                    skipped += 1;
+                    continue;
+                }
+
+                diesel::update(users::table.find(*id)).set(users::normalized_email.eq(normalized)).execute(&conn)?;
+                updated += 1;
+            }
+
+            println!("Updated {} user(s), skipped {} colliding", updated, skipped);
+        },
+        Command::GenerateKeys => unreachable!(),
+    }
+
+    Ok(())
+}
+
+fn find_user(email: &str, conn: &PgConnection) -> Result<User, AuthError> {
+    users::table
+        .filter(users::normalized_email.eq(emails::normalize(email)))
+        .first::<User>(conn)
+        .optional()?
+        .ok_or_else(|| AuthError::NotFound(format!("No user with email {}", email)))
+}
+
+fn update_roles(user_id: uuid::Uuid, roles: Vec<String>, conn: &PgConnection) -> Result<(), AuthError> {
+    let user: User = diesel::update(users::table.find(user_id))
+                            .set(users::roles.eq(roles))
+                            .get_result(conn)?;
+
+    println!("Roles for {}: {}", user.email, user.roles.join(", "));
+
+    Ok(())
+}
+
+#[derive(Deserialize)]
+struct ImportRecord {
+    email: String,
+    hash: String,
+    #[serde(default)]
+    roles: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct CsvRecord {
+    email: String,
+    hash: String,
+    #[serde(default)]
+    roles: String,
+}
+
+fn read_csv(path: &PathBuf) -> Result<Vec<ImportRecord>, AuthError> {
+    let mut reader = csv::Reader::from_path(path)
+        .map_err(|err| AuthError::ProcessError(format!("Could not read {}: {}", path.display(), err)))?;
+
+    reader
+        .deserialize::<CsvRecord>()
+        .enumerate()
+        .map(|(n, record)| {
+            let record = record.map_err(|err| AuthError::GenericError(format!("Row {}: {}", n + 1, err)))?;
+            let roles = record.roles.split(';').map(str::trim).filter(|role| !role.is_empty()).map(String::from).collect();
+
+            Ok(ImportRecord { email: record.email.trim().to_string(), hash: record.hash.trim().to_string(), roles })
+        })
+        .collect()
+}
+
+fn read_jsonl(path: &PathBuf) -> Result<Vec<ImportRecord>, AuthError> {
+    let file = File::open(path)
+        .map_err(|err| AuthError::ProcessError(format!("Could not read {}: {}", path.display(), err)))?;
+
+    BufReader::new(file)
+        .lines()
+        .enumerate()
+        .map(|(n, line)| line.map(|line| (n, line)))
+        .filter(|line| line.as_ref().map_or(true, |(_, line)| !line.trim().is_empty()))
+        .map(|line| {
+            let (n, line) = line.map_err(|err| AuthError::ProcessError(format!("Could not read {}: {}", path.display(), err)))?;
+
+            serde_json::from_str(&line).map_err(|err| AuthError::GenericError(format!("Line {}: {}", n + 1, err)))
+        })
+        .collect()
+}
+
+fn prompt_password() -> Result<String, AuthError> {
+    let password = rpassword::read_password_from_tty(Some("Password: "))
+        .map_err(|_| AuthError::ProcessError(String::from("Could not read password")))?;
+
+    if password.is_empty() {
+        Err(AuthError::GenericError(String::from("Password cannot be empty")))
+    } else {
+        Ok(password)
+    }
+}
+
+fn generate_keys() -> Result<(), AuthError> {
+    let mut rng = rand::thread_rng();
+    let mut session_key = [0u8; 32];
+    let mut secret_key = [0u8; 32];
+
+    rng.fill(&mut session_key);
+    rng.fill(&mut secret_key);
+
+    println!("SESSION_KEY={}", hex::encode(session_key));
+    println!("SECRET_KEY={}", hex::encode(secret_key));
+
+    Ok(())
+}
@@ -0,0 +1,101 @@
+use std::{
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        mpsc::{sync_channel, Receiver, SyncSender, TrySendError},
+        Arc,
+        Mutex
+    },
+    thread,
+    time::Instant
+};
+
+use futures::channel::oneshot;
+use tracing::warn;
+
+use crate::{errors::AuthError, metrics, vars};
+
+type Job = Box<dyn FnOnce() + Send>;
+
+
+// Runs password hashing/verification and the queries around it on threads of its own,
+// so a burst of sign-ins queues up here instead of tying up the actix workers
+// and the pool `web::block` shares with everything else.
+#[derive(Clone)]
+pub struct CredentialPool {
+    sender: SyncSender<Job>,
+}
+
+impl CredentialPool {
+    // `queue_size` jobs can wait for a worker, anything beyond that is turned away
+    pub fn new(workers: usize, queue_size: usize) -> Self {
+        let (sender, receiver) = sync_channel::<Job>(queue_size);
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        for n in 0..workers.max(1) {
+            let receiver = receiver.clone();
+
+            thread::Builder::new()
+                .name(format!("credential-worker-{}", n))
+                .spawn(move || work(receiver))
+                .expect("Failed to start a credential worker");
+        }
+
+        CredentialPool { sender }
+    }
+
+    // Sized with `CREDENTIAL_WORKERS` and `CREDENTIAL_QUEUE_SIZE`
+    pub fn from_env() -> Self {
+        CredentialPool::new(vars::credential_workers(), vars::credential_queue_size())
+    }
+
+    pub async fn run<F, T>(&self, f: F) -> Result<T, AuthError>
+    where
+        F: FnOnce() -> Result<T, AuthError> + Send + 'static,
+        T: Send + 'static,
+    {
+        let (tx, rx) = oneshot::channel();
+        let queued_at = Instant::now();
+        let job: Job = Box::new(move || {
+            metrics::CREDENTIAL_QUEUE_DEPTH.dec();
+            metrics::CREDENTIAL_QUEUE_SECONDS.observe(queued_at.elapsed().as_secs_f64());
+
+            // the caller may have gone away, e.g. the client disconnected
+            let _ = tx.send(f());
+        });
+
+        metrics::CREDENTIAL_QUEUE_DEPTH.inc();
+
+        if let Err(err) = self.sender.try_send(job) {
+            metrics::CREDENTIAL_QUEUE_DEPTH.dec();
+
+            return match err {
+                TrySendError::Full(_) => {
+                    metrics::CREDENTIAL_REJECTED.inc();
+
+                    Err(AuthError::TooManyRequests(String::from("Too many requests in progress, try again shortly")))
+                },
+                TrySendError::Disconnected(_) => Err(AuthError::ProcessError(String::from("Could not complete the process"))),
+            };
+        }
+
+        // the job panicked or the pool shut down, a fault on our side
+        rx.await.map_err(|_| AuthError::ProcessError(String::from("Could not complete the process")))?
+    }
+}
+
+
+fn work(receiver: Arc<Mutex<Receiver<Job>>>) {
+    loop {
+        let job = receiver.lock().unwrap().recv();
+
+        match job {
+            Ok(job) => {
+                // a panicking job drops its sender, which the caller sees as an error
+                if panic::catch_unwind(AssertUnwindSafe(job)).is_err() {
+                    warn!("A credential job panicked");
+                }
+            },
+            Err(_) => break,
+        }
+    }
+}
@@ -0,0 +1,71 @@
+use std::time::Duration;
+
+use actix_web::{error::BlockingError, web};
+use diesel::{
+    connection::SimpleConnection,
+    r2d2::{self, ConnectionManager, CustomizeConnection, PooledConnection},
+    Connection as _,
+    PgConnection
+};
+
+use crate::{errors::AuthError, metrics, models::Pool, vars};
+
+pub type Connection = PooledConnection<ConnectionManager<PgConnection>>;
+
+
+// Sizes the pool with `DATABASE_POOL_SIZE`, waits at most `DATABASE_CONNECT_TIMEOUT_MS`
+// for a connection and applies `DATABASE_STATEMENT_TIMEOUT_MS` to every connection
+pub fn connect(database_url: &str) -> Result<Pool, AuthError> {
+    let manager = ConnectionManager::<PgConnection>::new(database_url);
+
+    r2d2::Pool::builder()
+        .max_size(vars::database_pool_size())
+        .connection_timeout(Duration::from_millis(vars::database_connect_timeout_ms()))
+        .connection_customizer(Box::new(StatementTimeout(vars::database_statement_timeout_ms())))
+        .build(manager)
+        .map_err(|err| AuthError::ProcessError(format!("Could not create a database connection pool: {}", err)))
+}
+
+// A connection of its own, without `DATABASE_STATEMENT_TIMEOUT_MS`, for work that may
+// rightly take longer than a request: migrations wait on their lock while another replica
+// migrates, and DDL and backfills can be slow
+pub fn connect_unpooled(database_url: &str) -> Result<PgConnection, AuthError> {
+    PgConnection::establish(database_url)
+        .map_err(|err| AuthError::ProcessError(format!("Could not connect to the database: {}", err)))
+}
+
+// A connection from the pool, or `ServiceUnavailable` when none frees up in time
+pub fn get(pool: &Pool) -> Result<Connection, AuthError> {
+    pool.get().map_err(|_| {
+        metrics::DB_POOL_TIMEOUTS.inc();
+
+        AuthError::ServiceUnavailable(String::from("The service is busy, try again shortly"))
+    })
+}
+
+// Runs blocking database work off the actix workers
+pub async fn run<F, T>(f: F) -> Result<T, AuthError>
+where
+    F: FnOnce() -> Result<T, AuthError> + Send + 'static,
+    T: Send + 'static,
+{
+    web::block(f).await.map_err(|err| match err {
+        BlockingError::Error(auth_error) => auth_error,
+        BlockingError::Canceled => AuthError::ProcessError(String::from("Could not complete the process")),
+    })
+}
+
+
+#[derive(Debug)]
+struct StatementTimeout(u64);
+
+impl CustomizeConnection<PgConnection, r2d2::Error> for StatementTimeout {
+    fn on_acquire(&self, conn: &mut PgConnection) -> Result<(), r2d2::Error> {
+        // 0 leaves the server's default in place
+        if self.0 == 0 {
+            return Ok(());
+        }
+
+        conn.batch_execute(&format!("SET statement_timeout = {}", self.0)).map_err(r2d2::Error::QueryError)
+    }
+}
@@ -0,0 +1,269 @@
+use actix_web::{web, HttpRequest, HttpResponse};
+use actix_session::Session;
+use diesel::prelude::*;
+use schemars::JsonSchema;
+use serde::Deserialize;
+use uuid::Uuid;
+use validator::Validate;
+use yarte::Template;
+
+use crate::{
+    db,
+    email_service::{send_email_change_mail, send_email_change_notice, SharedMailer},
+    emails,
+    errors::AuthError,
+    models::{EmailChange, Pool, SessionUser, User},
+    schema::{email_changes, users},
+    templates::{ChangeEmail, Notice},
+    negotiation::{wants_json, Format},
+    utils::{get_current_user, set_current_user, to_sign_in},
+    validation::{normalize_text, Normalize, Validated},
+    vars
+};
+
+
+#[derive(Debug, Deserialize, JsonSchema, Validate)]
+pub struct EmailData {
+    #[validate(
+        email(message = "Enter a valid email address"),
+        length(max = 50, message = "Email addresses can be at most 50 characters long")
+    )]
+    pub email: String,
+}
+
+impl Normalize for EmailData {
+    fn normalize(&mut self) {
+        self.email = normalize_text(&self.email);
+    }
+}
+
+pub async fn show_email_form(session: Session) -> HttpResponse {
+    match get_current_user(&session) {
+        Ok(user) => {
+            let t = ChangeEmail { email: user.email, sent: false, error: None, email_errors: vec![] };
+
+            HttpResponse::Ok().content_type("text/html; charset=utf-8").body(t.call().unwrap())
+        },
+        Err(_) => to_sign_in("/me/email"),
+    }
+}
+
+pub async fn request_email_change(session: Session,
+                                  data: Validated<EmailData>,
+                                  pool: web::Data<Pool>,
+                                  mailer: web::Data<SharedMailer>) -> Result<HttpResponse, AuthError> {
+    let user = get_current_user(&session)?;
+    let email = data.into_inner()?.email;
+
+    db::run(move || create_email_change(&user, email, &pool, &mailer)).await?;
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+pub async fn request_email_change_for_browser(session: Session,
+                                              data: Validated<EmailData>,
+                                              pool: web::Data<Pool>,
+                                              mailer: web::Data<SharedMailer>) -> Result<HttpResponse, AuthError> {
+    let user = match get_current_user(&session) {
+        Ok(user) => user,
+        Err(_) => return Ok(to_sign_in("/me/email")),
+    };
+    let current_email = user.email.clone();
+    let result = match data.into_inner() {
+        Ok(data) => db::run(move || create_email_change(&user, data.email, &pool, &mailer)).await,
+        Err(err) => Err(err),
+    };
+    let template = match result {
+        Ok(_) => ChangeEmail { email: current_email, sent: true, error: None, email_errors: vec![] },
+        Err(err @ AuthError::ValidationError(_)) => {
+            ChangeEmail { email: current_email, sent: false, error: None, email_errors: err.field_messages("email") }
+        },
+        Err(err) => ChangeEmail { email: current_email, sent: false, error: Some(err.user_message()), email_errors: vec![] },
+    };
+
+    Ok(HttpResponse::Ok().content_type("text/html; charset=utf-8").body(template.call().unwrap()))
+}
+
+pub async fn confirm_email_change(session: Session,
+                                  path_id: web::Path<String>,
+                                  req: HttpRequest,
+                                  pool: web::Data<Pool>) -> Result<HttpResponse, AuthError> {
+    let result = db::run(move || apply_email_change(&path_id.into_inner(), &pool)).await;
+
+    respond_to_change(result, &session, &req, "Your email address has been changed")
+}
+
+pub async fn revert_email_change(session: Session,
+                                 path_id: web::Path<String>,
+                                 req: HttpRequest,
+                                 pool: web::Data<Pool>) -> Result<HttpResponse, AuthError> {
+    let result = db::run(move || undo_email_change(&path_id.into_inner(), &pool)).await;
+
+    respond_to_change(result, &session, &req, "Your email address has been restored")
+}
+
+
+fn respond_to_change(result: Result<SessionUser, AuthError>,
+                     session: &Session,
+                     req: &HttpRequest,
+                     success_title: &str) -> Result<HttpResponse, AuthError> {
+    let is_json = wants_json(req, Format::Html);
+
+    match result {
+        Ok(user) => {
+            // refresh the session if it belongs to the affected user
+            if let Ok(current_user) = get_current_user(session) {
+                if current_user.id == user.id {
+                    set_current_user(session, &user);
+                }
+            }
+
+            if is_json {
+                Ok(HttpResponse::Ok().json(user))
+            } else {
+                let t = Notice {
+                    title: String::from(success_title),
+                    message: format!("Your email is now {}", user.email),
+                    success: true
+                };
+
+                Ok(HttpResponse::Ok().content_type("text/html; charset=utf-8").body(t.call().unwrap()))
+            }
+        },
+        Err(auth_error) => {
+            if is_json {
+                Err(auth_error)
+            } else {
+                let t = Notice {
+                    title: String::from("Email address not changed"),
+                    message: auth_error.user_message(),
+                    success: false
+                };
+
+                Ok(HttpResponse::Ok().content_type("text/html; charset=utf-8").body(t.call().unwrap()))
+            }
+        },
+    }
+}
+
+fn create_email_change(user: &SessionUser,
+                       new_email: String,
+                       pool: &web::Data<Pool>,
+                       mailer: &web::Data<SharedMailer>) -> Result<(), AuthError> {
+    let conn = &db::get(pool)?;
+
+    if new_email == user.email {
+        return Err(AuthError::GenericError(String::from("This is already your email address")));
+    }
+
+    ensure_email_is_free(&new_email, user.id, conn)?;
+
+    // only the most recent request can be confirmed
+    diesel::delete(
+        email_changes::table
+            .filter(email_changes::user_id.eq(user.id))
+            .filter(email_changes::confirmed_at.is_null())
+    )
+    .execute(conn)?;
+
+    let change: EmailChange = diesel::insert_into(email_changes::table)
+                                    .values(&EmailChange::from(user, new_email))
+                                    .get_result(conn)?;
+
+    send_email_change_mail(mailer.get_ref().as_ref(), &change)?;
+    send_email_change_notice(mailer.get_ref().as_ref(), &change)
+}
+
+fn apply_email_change(path_id: &str, pool: &web::Data<Pool>) -> Result<SessionUser, AuthError> {
+    let path_uuid = Uuid::parse_str(path_id)?;
+    let conn = &db::get(pool)?;
+
+    conn.transaction(|| {
+        let change = email_changes::table
+            .filter(email_changes::id.eq(path_uuid))
+            .filter(email_changes::confirmed_at.is_null())
+            .first::<EmailChange>(conn)
+            .optional()?
+            .ok_or_else(|| AuthError::NotFound(String::from("Email change request not found")))?;
+        let now = chrono::Local::now().naive_local();
+
+        if change.expires_at < now {
+            return Err(AuthError::AuthenticationError(String::from("Invalid confirmation")));
+        }
+
+        ensure_email_is_free(&change.new_email, change.user_id, conn)?;
+
+        let user: User = diesel::update(users::table.find(change.user_id))
+                                .set((
+                                    users::email.eq(&change.new_email),
+                                    users::normalized_email.eq(emails::normalize(&change.new_email))
+                                ))
+                                .get_result(conn)?;
+
+        diesel::update(email_changes::table.find(change.id))
+            .set(email_changes::confirmed_at.eq(Some(now)))
+            .execute(conn)?;
+
+        Ok(user.into())
+    })
+}
+
+fn undo_email_change(path_id: &str, pool: &web::Data<Pool>) -> Result<SessionUser, AuthError> {
+    let path_uuid = Uuid::parse_str(path_id)?;
+    let conn = &db::get(pool)?;
+
+    conn.transaction(|| {
+        let change = email_changes::table
+            .filter(email_changes::revert_id.eq(path_uuid))
+            .first::<EmailChange>(conn)
+            .optional()?
+            .ok_or_else(|| AuthError::NotFound(String::from("Email change request not found")))?;
+
+        let user: User = match change.confirmed_at {
+            // the change never went through, so cancelling it is enough
+            None => users::table.find(change.user_id).get_result(conn)?,
+            Some(confirmed_at) => {
+                let revert_deadline = confirmed_at + chrono::Duration::days(vars::email_change_revert_days());
+
+                if revert_deadline < chrono::Local::now().naive_local() {
+                    return Err(AuthError::AuthenticationError(String::from("This link has expired")));
+                }
+
+                ensure_email_is_free(&change.old_email, change.user_id, conn)?;
+
+                diesel::update(
+                    users::table
+                        .filter(users::id.eq(change.user_id))
+                        .filter(users::email.eq(&change.new_email))
+                )
+                .set((
+                    users::email.eq(&change.old_email),
+                    users::normalized_email.eq(emails::normalize(&change.old_email))
+                ))
+                .get_result::<User>(conn)
+                .optional()?
+                .ok_or_else(|| AuthError::GenericError(String::from("The email address has changed since")))?
+            }
+        };
+
+        diesel::delete(email_changes::table.filter(email_changes::user_id.eq(change.user_id)))
+            .execute(conn)?;
+
+        Ok(user.into())
+    })
+}
+
+// Taken by anyone but `owner`, who may be changing just the case of their own address
+fn ensure_email_is_free(address: &str, owner: Uuid, conn: &PgConnection) -> Result<(), AuthError> {
+    let taken = users::table
+        .filter(users::normalized_email.eq(emails::normalize(address)))
+        .filter(users::id.ne(owner))
+        .count()
+        .get_result::<i64>(conn)?;
+
+    if taken > 0 {
+        Err(AuthError::DuplicateValue(String::from("Email is already in use")))
+    } else {
+        Ok(())
+    }
+}
@@ -1,3 +1,5 @@
+use std::sync::Arc;
+
 use lettre::{
   Email, 
   SmtpClient, 
@@ -10,13 +12,32 @@ use lettre::{
   }
 };
 use native_tls::{Protocol, TlsConnector};
+use tracing::{info, warn};
+
+use crate::{models::{AccountDeletion, Confirmation, EmailChange, PasswordReset}, errors::AuthError, metrics, telemetry::redact_email, vars};
+
+
+pub struct Message {
+  pub to: String,
+  pub subject: String,
+  pub plain_text: String,
+  pub html_text: String,
+}
+
+// Delivers the service's emails. Implement this to send mail through something other than SMTP.
+pub trait Mailer: Send + Sync {
+  fn send(&self, message: Message) -> Result<(), AuthError>;
+}
 
-use crate::{models::Confirmation, errors::AuthError, vars};
+pub type SharedMailer = Arc<dyn Mailer>;
 
+// Sends mail through the SMTP server configured with the `SMTP_*` variables
+pub struct SmtpMailer;
 
-pub fn send_confirmation_mail(confirmation: &Confirmation) -> Result<(), AuthError> {
+
+pub fn send_confirmation_mail(mailer: &dyn Mailer, confirmation: &Confirmation) -> Result<(), AuthError> {
   let domain_url = vars::domain_url();
-  let expires = confirmation.expires_at.format("%I:%M %p %A, %-d %B, %C%y").to_string();
+  let expires = format_expiry(&confirmation.expires_at);
   let html_text = format!(
       "Please click on the link below to complete registration. <br/>
        <a href=\"{domain}/register/{id}\">Complete registration</a> <br/>
@@ -34,36 +55,204 @@ pub fn send_confirmation_mail(confirmation: &Confirmation) -> Result<(), AuthErr
       expires=expires
   );
 
-  let email = Email::builder()
-                    .to(confirmation.email.clone())
-                    .from(("noreply@auth-service.com", vars::smtp_sender_name()))
-                    .subject("Complete your registration on our one-of-a-kind Auth Service")
-                    .text(plain_text)
-                    .html(html_text)
-                    .build()
-                    .unwrap();
-
-  let smtp_host = vars::smtp_host();
-  let mut tls_builder = TlsConnector::builder();
-  tls_builder.min_protocol_version(Some(Protocol::Tlsv10));
-  let tls_parameters = ClientTlsParameters::new(smtp_host.clone(), tls_builder.build().unwrap());
-
-  let mut mailer = SmtpClient::new((smtp_host.as_str(), vars::smtp_port()), ClientSecurity::Required(tls_parameters))
-                              .unwrap()
-                              .authentication_mechanism(Mechanism::Login)
-                              .credentials(Credentials::new(vars::smtp_username(), vars::smtp_password()))
-                              .connection_reuse(ConnectionReuseParameters::ReuseUnlimited)
-                              .transport();
-
-  let result = mailer.send(email);
-
-  if result.is_ok() {
-      println!("Email sent");
-
-      Ok(())
-  } else {
-      println!("Could not send email: {:?}", result);
-
-      Err(AuthError::ProcessError(String::from("Could not send confirmation email")))
+  deliver(mailer, "confirmation", Message {
+    to: confirmation.email.clone(),
+    subject: String::from("Complete your registration on our one-of-a-kind Auth Service"),
+    plain_text,
+    html_text,
+  })
+  .map_err(|_| AuthError::ProcessError(String::from("Could not send confirmation email")))
+}
+
+// Sent instead of a registration link when the address already has an account, so the
+// register form answers the same either way
+pub fn send_registered_notice(mailer: &dyn Mailer, email: &str) -> Result<(), AuthError> {
+  let domain_url = vars::domain_url();
+  let html_text = format!(
+      "Someone, hopefully you, tried to create an account with this email address, but it already has one. <br/>
+       <a href=\"{domain}/signin\">Sign in</a> or <a href=\"{domain}/password/reset\">reset your password</a>. <br/>
+      If it wasn't you, you can ignore this email.",
+      domain=domain_url
+  );
+  let plain_text = format!(
+      "Someone, hopefully you, tried to create an account with this email address, but it already has one.\n
+      Sign in at {domain}/signin or reset your password at {domain}/password/reset.\n
+      If it wasn't you, you can ignore this email.",
+      domain=domain_url
+  );
+
+  deliver(mailer, "registered_notice", Message {
+    to: String::from(email),
+    subject: String::from("You already have an account"),
+    plain_text,
+    html_text,
+  })
+  // worded like a failed confirmation, which is what the caller thinks was sent
+  .map_err(|_| AuthError::ProcessError(String::from("Could not send confirmation email")))
+}
+
+pub fn send_email_change_mail(mailer: &dyn Mailer, change: &EmailChange) -> Result<(), AuthError> {
+  let domain_url = vars::domain_url();
+  let expires = format_expiry(&change.expires_at);
+  let html_text = format!(
+      "Please click on the link below to confirm your new email address. <br/>
+       <a href=\"{domain}/me/email/{id}\">Confirm email address</a> <br/>
+      This link expires on <strong>{expires}</strong>",
+      domain=domain_url,
+      id=change.id,
+      expires=expires
+  );
+  let plain_text = format!(
+      "Please visit the link below to confirm your new email address:\n
+      {domain}/me/email/{id}\n
+      This link expires on {expires}.",
+      domain=domain_url,
+      id=change.id,
+      expires=expires
+  );
+
+  deliver(mailer, "email_change", Message {
+    to: change.new_email.clone(),
+    subject: String::from("Confirm your new email address"),
+    plain_text,
+    html_text,
+  })
+  .map_err(|_| AuthError::ProcessError(String::from("Could not send email change confirmation")))
+}
+
+pub fn send_email_change_notice(mailer: &dyn Mailer, change: &EmailChange) -> Result<(), AuthError> {
+  let domain_url = vars::domain_url();
+  let html_text = format!(
+      "A request was made to change the email address on your account to <strong>{new_email}</strong>. <br/>
+      If this wasn't you, click on the link below to undo the change. <br/>
+       <a href=\"{domain}/me/email/{id}/revert\">Keep my current email address</a>",
+      domain=domain_url,
+      id=change.revert_id,
+      new_email=change.new_email
+  );
+  let plain_text = format!(
+      "A request was made to change the email address on your account to {new_email}.\n
+      If this wasn't you, visit the link below to undo the change:\n
+      {domain}/me/email/{id}/revert",
+      domain=domain_url,
+      id=change.revert_id,
+      new_email=change.new_email
+  );
+
+  deliver(mailer, "email_change_notice", Message {
+    to: change.old_email.clone(),
+    subject: String::from("Your email address is being changed"),
+    plain_text,
+    html_text,
+  })
+  .map_err(|_| AuthError::ProcessError(String::from("Could not send email change notice")))
+}
+
+pub fn send_account_deletion_mail(mailer: &dyn Mailer, email: &str, deletion: &AccountDeletion) -> Result<(), AuthError> {
+  let domain_url = vars::domain_url();
+  let scheduled_for = format_expiry(&deletion.scheduled_for);
+  let html_text = format!(
+      "Your account is scheduled for deletion on <strong>{scheduled_for}</strong>. <br/>
+      If you change your mind, click on the link below before then. <br/>
+       <a href=\"{domain}/me/delete/{id}/cancel\">Keep my account</a>",
+      domain=domain_url,
+      id=deletion.id,
+      scheduled_for=scheduled_for
+  );
+  let plain_text = format!(
+      "Your account is scheduled for deletion on {scheduled_for}.\n
+      If you change your mind, visit the link below before then:\n
+      {domain}/me/delete/{id}/cancel",
+      domain=domain_url,
+      id=deletion.id,
+      scheduled_for=scheduled_for
+  );
+
+  deliver(mailer, "account_deletion", Message {
+    to: String::from(email),
+    subject: String::from("Your account is scheduled for deletion"),
+    plain_text,
+    html_text,
+  })
+  .map_err(|_| AuthError::ProcessError(String::from("Could not send account deletion email")))
+}
+
+pub fn send_password_reset_mail(mailer: &dyn Mailer, email: &str, reset: &PasswordReset) -> Result<(), AuthError> {
+  let domain_url = vars::domain_url();
+  let expires = format_expiry(&reset.expires_at);
+  let html_text = format!(
+      "Please click on the link below to choose a new password. <br/>
+       <a href=\"{domain}/password/reset/{id}\">Reset password</a> <br/>
+      This link expires on <strong>{expires}</strong>. If you didn't ask for it, you can ignore this email.",
+      domain=domain_url,
+      id=reset.id,
+      expires=expires
+  );
+  let plain_text = format!(
+      "Please visit the link below to choose a new password:\n
+      {domain}/password/reset/{id}\n
+      This link expires on {expires}. If you didn't ask for it, you can ignore this email.",
+      domain=domain_url,
+      id=reset.id,
+      expires=expires
+  );
+
+  deliver(mailer, "password_reset", Message {
+    to: String::from(email),
+    subject: String::from("Reset your password"),
+    plain_text,
+    html_text,
+  })
+  .map_err(|_| AuthError::ProcessError(String::from("Could not send password reset email")))
+}
+
+
+fn deliver(mailer: &dyn Mailer, kind: &str, message: Message) -> Result<(), AuthError> {
+  let result = mailer.send(message);
+
+  metrics::EMAILS.with_label_values(&[kind, if result.is_ok() { "sent" } else { "failed" }]).inc();
+
+  result
+}
+
+fn format_expiry(expires_at: &chrono::NaiveDateTime) -> String {
+  expires_at.format("%I:%M %p %A, %-d %B, %C%y").to_string()
+}
+
+impl Mailer for SmtpMailer {
+  fn send(&self, message: Message) -> Result<(), AuthError> {
+    let recipient = redact_email(&message.to);
+    let email = Email::builder()
+                      .to(message.to)
+                      .from(("noreply@auth-service.com", vars::smtp_sender_name()))
+                      .subject(message.subject)
+                      .text(message.plain_text)
+                      .html(message.html_text)
+                      .build()
+                      .unwrap();
+
+    let smtp_host = vars::smtp_host();
+    let mut tls_builder = TlsConnector::builder();
+    tls_builder.min_protocol_version(Some(Protocol::Tlsv10));
+    let tls_parameters = ClientTlsParameters::new(smtp_host.clone(), tls_builder.build().unwrap());
+
+    let mut mailer = SmtpClient::new((smtp_host.as_str(), vars::smtp_port()), ClientSecurity::Required(tls_parameters))
+                                .unwrap()
+                                .authentication_mechanism(Mechanism::Login)
+                                .credentials(Credentials::new(vars::smtp_username(), vars::smtp_password()))
+                                .connection_reuse(ConnectionReuseParameters::ReuseUnlimited)
+                                .transport();
+
+    let result = mailer.send(email);
+
+    if result.is_ok() {
+        info!(to = %recipient, "Email sent");
+
+        Ok(())
+    } else {
+        warn!(to = %recipient, error = ?result.err(), "Could not send email");
+
+        Err(AuthError::ProcessError(String::from("Could not send email")))
+    }
   }
-}
\ No newline at end of file
+}
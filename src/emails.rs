@@ -0,0 +1,188 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use diesel::{pg::PgConnection, prelude::*, sql_types::BigInt};
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::{
+    errors::AuthError,
+    schema::{confirmations, users},
+    vars
+};
+
+const GMAIL_DOMAINS: [&str; 2] = ["gmail.com", "googlemail.com"];
+
+
+// Users whose addresses are one account under the current rules
+#[derive(Debug, Serialize)]
+pub struct EmailReport {
+    pub total: usize,
+    pub collisions: BTreeMap<String, Vec<CollidingUser>>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CollidingUser {
+    pub id: Uuid,
+    pub email: String,
+}
+
+#[derive(QueryableByName)]
+struct Count {
+    #[sql_type = "BigInt"]
+    count: i64,
+}
+
+// The form an address is identified by, stored next to the address as typed. The domain
+// is lowercased and IDNA encoded, so "ALICE@Bücher.example" becomes
+// "alice@xn--bcher-kva.example"; the local part is lowercased and, for Gmail, stripped of
+// dots according to EMAIL_FOLD_LOCAL_CASE and EMAIL_IGNORE_GMAIL_DOTS.
+pub fn normalize(email: &str) -> String {
+    let email = email.trim();
+    let (local, domain) = match email.rfind('@') {
+        Some(at) => (&email[..at], &email[at + 1..]),
+        None => return email.to_lowercase(),
+    };
+    let domain = domain.trim_end_matches('.').to_lowercase();
+    // invalid names, e.g. with disallowed code points, are kept as they are
+    let domain = idna::domain_to_ascii(&domain).unwrap_or(domain);
+    let mut local = if vars::email_fold_local_case() { local.to_lowercase() } else { String::from(local) };
+
+    if vars::email_ignore_gmail_dots() && GMAIL_DOMAINS.contains(&domain.as_str()) {
+        local.retain(|c| c != '.');
+    }
+
+    format!("{}@{}", local, domain)
+}
+
+// Groups addresses that normalize the same. Only one user of each group can keep the
+// address, the others have to be removed or given another one.
+pub fn report<'a, I: IntoIterator<Item = (Uuid, &'a str)>>(users: I) -> EmailReport {
+    let mut total = 0;
+    let mut groups: BTreeMap<String, Vec<CollidingUser>> = BTreeMap::new();
+
+    for (id, email) in users {
+        total += 1;
+        groups.entry(normalize(email)).or_default().push(CollidingUser { id, email: String::from(email) });
+    }
+
+    let collisions = groups.into_iter().filter(|(_, users)| users.len() > 1).collect();
+
+    EmailReport { total, collisions }
+}
+
+// Fills in the normalized emails that the normalized_emails migration adds empty, so
+// `normalize` is their only definition, then makes them required. Run by `migrations::run`.
+// Nothing changes while users collide.
+pub fn backfill(conn: &PgConnection) -> Result<(), AuthError> {
+    if is_backfilled(conn)? {
+        return Ok(());
+    }
+
+    conn.transaction(|| {
+        let records = users::table.select((users::id, users::email)).load::<(Uuid, String)>(conn)?;
+        let collisions = report(records.iter().map(|(id, email)| (*id, email.as_str()))).collisions;
+
+        if !collisions.is_empty() {
+            return Err(AuthError::ProcessError(format!(
+                "{} email address(es) belong to more than one user, list them with `auth-admin email-collisions` \
+                 and resolve them before migrating",
+                collisions.len()
+            )));
+        }
+
+        for (id, email) in &records {
+            diesel::update(users::table.find(*id)).set(users::normalized_email.eq(normalize(email))).execute(conn)?;
+        }
+
+        // pending confirmations are short-lived, so only the latest one per address is kept
+        let pending = confirmations::table
+            .select((confirmations::id, confirmations::email))
+            .order(confirmations::sent_at.desc())
+            .load::<(Uuid, String)>(conn)?;
+        let mut seen = BTreeSet::new();
+
+        for (id, email) in pending {
+            let normalized = normalize(&email);
+
+            if seen.insert(normalized.clone()) {
+                diesel::update(confirmations::table.find(id))
+                    .set(confirmations::normalized_email.eq(normalized))
+                    .execute(conn)?;
+            } else {
+                diesel::delete(confirmations::table.find(id)).execute(conn)?;
+            }
+        }
+
+        diesel::sql_query("ALTER TABLE users ALTER COLUMN normalized_email SET NOT NULL").execute(conn)?;
+        diesel::sql_query("ALTER TABLE confirmations ALTER COLUMN normalized_email SET NOT NULL").execute(conn)?;
+
+        Ok(())
+    })
+}
+
+// Until `backfill` has run nobody can be found by their email
+pub fn is_backfilled(conn: &PgConnection) -> Result<bool, AuthError> {
+    let nullable = diesel::sql_query(
+        "SELECT count(*) AS count FROM information_schema.columns \
+         WHERE table_schema = current_schema() AND column_name = 'normalized_email' AND is_nullable = 'YES'"
+    )
+    .get_result::<Count>(conn)?;
+
+    Ok(nullable.count == 0)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // every test sets the same values, so they can run side by side
+    fn configure() {
+        std::env::set_var("EMAIL_FOLD_LOCAL_CASE", "true");
+        std::env::set_var("EMAIL_IGNORE_GMAIL_DOTS", "true");
+    }
+
+    #[test]
+    fn domains_are_lowercased_and_idna_encoded() {
+        configure();
+
+        assert_eq!(normalize("  ALICE@Bücher.Example. "), "alice@xn--bcher-kva.example");
+        assert_eq!(normalize("alice@xn--bcher-kva.example"), "alice@xn--bcher-kva.example");
+    }
+
+    #[test]
+    fn gmail_dots_are_ignored_only_at_gmail() {
+        configure();
+
+        assert_eq!(normalize("A.Lice@GoogleMail.com"), "alice@googlemail.com");
+        assert_eq!(normalize("a.lice@gmail.com"), normalize("alice@gmail.com"));
+        assert_eq!(normalize("a.lice@example.com"), "a.lice@example.com");
+    }
+
+    #[test]
+    fn the_last_at_sign_splits_the_address() {
+        configure();
+
+        assert_eq!(normalize("\"A@B\"@Example.com"), "\"a@b\"@example.com");
+        assert_eq!(normalize("Not An Address"), "not an address");
+    }
+
+    #[test]
+    fn colliding_addresses_are_reported_together() {
+        configure();
+
+        let (alice, alice2, bob) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        let report = report(vec![
+            (alice, "alice@gmail.com"),
+            (alice2, "A.lice@gmail.com"),
+            (bob, "bob@example.com"),
+        ]);
+
+        assert_eq!(report.total, 3);
+        assert_eq!(report.collisions.len(), 1);
+        assert_eq!(
+            report.collisions["alice@gmail.com"].iter().map(|user| user.id).collect::<Vec<_>>(),
+            vec![alice, alice2]
+        );
+    }
+}
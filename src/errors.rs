@@ -1,47 +1,174 @@
-use actix_web::{HttpResponse, ResponseError};
+use actix_web::{http::StatusCode, HttpResponse, ResponseError};
 use derive_more::Display;
 use diesel::result::{DatabaseErrorKind, Error as DBError};
+use schemars::JsonSchema;
+use serde::Serialize;
 use std::convert::From;
+use tracing::warn;
 use uuid::Error as UuidError;
 
+pub const PROBLEM_CONTENT_TYPE: &str = "application/problem+json";
+
+// A problem with one of the submitted fields
+#[derive(Clone, Debug, Serialize, JsonSchema)]
+pub struct FieldError {
+    pub field: String,
+    pub message: String,
+}
+
+// The body of every JSON error response, an RFC 7807 problem document. `code` is stable
+// and documented in the README, clients should match on it rather than on `detail`.
+#[derive(Clone, Debug, Serialize, JsonSchema)]
+pub struct Problem {
+    #[serde(rename = "type")]
+    pub problem_type: String,
+    pub title: &'static str,
+    pub status: u16,
+    pub detail: String,
+    pub code: &'static str,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub errors: Vec<FieldError>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub change_password: Option<String>,
+}
+
+// Displayed messages are shown to users as they are, so they don't carry the variant name
 #[derive(Clone, Debug, Display)]
 pub enum AuthError {
-    #[display(fmt = "DuplicateValue: {}", _0)]
+    #[display(fmt = "{}", _0)]
     DuplicateValue(String),
 
-    #[display(fmt = "BadId")]
+    #[display(fmt = "Invalid ID")]
     BadId,
 
-    #[display(fmt = "NotFound: {}", _0)]
+    #[display(fmt = "{}", _0)]
     NotFound(String),
 
-    #[display(fmt = "ProcessError: {}", _0)]
+    #[display(fmt = "{}", _0)]
     ProcessError(String),
 
-    #[display(fmt = "AuthenticationError: {}", _0)]
+    #[display(fmt = "{}", _0)]
     AuthenticationError(String),
 
-    #[display(fmt = "GenericError: {}", _0)]
+    #[display(fmt = "{}", _0)]
     GenericError(String),
+
+    #[display(fmt = "{}", _0)]
+    TooManyRequests(String),
+
+    #[display(fmt = "{}", _0)]
+    ServiceUnavailable(String),
+
+    #[display(fmt = "{}", "messages(_0)")]
+    ValidationError(Vec<FieldError>),
+
+    // Holds where the password can be changed
+    #[display(fmt = "Your password has expired and must be changed")]
+    PasswordExpired(String),
 }
 
+impl AuthError {
+    // The messages for one field, e.g. to show next to it in a form
+    pub fn field_messages(&self, field: &str) -> Vec<String> {
+        match self {
+            AuthError::ValidationError(errors) => {
+                errors.iter().filter(|error| error.field == field).map(|error| error.message.clone()).collect()
+            },
+            _ => vec![],
+        }
+    }
 
-impl ResponseError for AuthError {
-    fn error_response(&self) -> HttpResponse {
-        match self { 
-            AuthError::BadId => HttpResponse::BadRequest().json("Invalid ID"),
+    // The stable, machine-readable code clients match on
+    pub fn code(&self) -> &'static str {
+        match self {
+            AuthError::DuplicateValue(_) => "duplicate_value",
+            AuthError::BadId => "invalid_id",
+            AuthError::NotFound(_) => "not_found",
+            AuthError::ProcessError(_) => "internal_error",
+            AuthError::AuthenticationError(_) => "authentication_failed",
+            AuthError::GenericError(_) => "bad_request",
+            AuthError::TooManyRequests(_) => "too_many_requests",
+            AuthError::ServiceUnavailable(_) => "service_unavailable",
+            AuthError::ValidationError(_) => "validation_failed",
+            AuthError::PasswordExpired(_) => "password_expired",
+        }
+    }
 
-            AuthError::NotFound(ref message) => HttpResponse::NotFound().json(message),
+    pub fn title(&self) -> &'static str {
+        match self {
+            AuthError::DuplicateValue(_) => "Duplicate value",
+            AuthError::BadId => "Invalid ID",
+            AuthError::NotFound(_) => "Not found",
+            AuthError::ProcessError(_) => "Internal error",
+            AuthError::AuthenticationError(_) => "Authentication failed",
+            AuthError::GenericError(_) => "Bad request",
+            AuthError::TooManyRequests(_) => "Too many requests",
+            AuthError::ServiceUnavailable(_) => "Service unavailable",
+            AuthError::ValidationError(_) => "Validation failed",
+            AuthError::PasswordExpired(_) => "Password expired",
+        }
+    }
 
-            AuthError::ProcessError(ref message) => HttpResponse::InternalServerError().json(message),
+    // The message to show the user. Internal errors' messages are for the logs, they may
+    // describe the service's internals.
+    pub fn user_message(&self) -> String {
+        match self {
+            AuthError::ProcessError(_) => String::from("Something went wrong, try again later"),
+            _ => self.to_string(),
+        }
+    }
+
+    pub fn problem(&self, request_id: Option<String>) -> Problem {
+        let detail = self.user_message();
+
+        Problem {
+            problem_type: format!("urn:auth-service:problem:{}", self.code()),
+            title: self.title(),
+            status: self.status_code().as_u16(),
+            detail,
+            code: self.code(),
+            errors: match self {
+                AuthError::ValidationError(errors) => errors.clone(),
+                _ => vec![],
+            },
+            request_id,
+            change_password: match self {
+                AuthError::PasswordExpired(url) => Some(url.clone()),
+                _ => None,
+            },
+        }
+    }
 
-            AuthError::AuthenticationError(ref message) => HttpResponse::Unauthorized().json(message),
+    pub fn problem_response(&self, request_id: Option<String>) -> HttpResponse {
+        HttpResponse::build(self.status_code())
+            .content_type(PROBLEM_CONTENT_TYPE)
+            .json(self.problem(request_id))
+    }
+}
 
-            AuthError::DuplicateValue(ref message) => HttpResponse::BadRequest().json(message),
 
-            AuthError::GenericError(ref message) => HttpResponse::BadRequest().json(message),
+impl ResponseError for AuthError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            AuthError::BadId => StatusCode::BAD_REQUEST,
+            AuthError::NotFound(_) => StatusCode::NOT_FOUND,
+            AuthError::ProcessError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            AuthError::AuthenticationError(_) => StatusCode::UNAUTHORIZED,
+            AuthError::DuplicateValue(_) => StatusCode::BAD_REQUEST,
+            AuthError::GenericError(_) => StatusCode::BAD_REQUEST,
+            AuthError::TooManyRequests(_) => StatusCode::TOO_MANY_REQUESTS,
+            AuthError::ServiceUnavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            AuthError::ValidationError(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            AuthError::PasswordExpired(_) => StatusCode::FORBIDDEN,
         }
     }
+
+    // `telemetry::RequestTracing` replaces this with a response carrying the request id
+    fn error_response(&self) -> HttpResponse {
+        self.problem_response(None)
+    }
 }
 
 
@@ -56,14 +183,42 @@ impl From<DBError> for AuthError {
         // We only care about UniqueViolations
         match error {
             DBError::DatabaseError(kind, info) => {
-                let message = info.details().unwrap_or_else(|| info.message()).to_string();
+                // Postgres' own wording names tables and columns, and the values that clashed,
+                // so it's only logged
+                warn!(
+                    constraint = ?info.constraint_name(),
+                    "Database error: {} {}",
+                    info.message(),
+                    info.details().unwrap_or_default()
+                );
 
                 match kind {
-                    DatabaseErrorKind::UniqueViolation => AuthError::DuplicateValue(message),
-                    _ => AuthError::GenericError(message)
+                    DatabaseErrorKind::UniqueViolation => AuthError::DuplicateValue(String::from(
+                        duplicate_message(info.constraint_name())
+                    )),
+                    // query_canceled, raised when DATABASE_STATEMENT_TIMEOUT_MS is exceeded
+                    _ if info.message().contains("statement timeout") => {
+                        AuthError::ServiceUnavailable(String::from("The service is busy, try again shortly"))
+                    },
+                    _ => AuthError::ProcessError(String::from("Some database error occured"))
                 }                
             }
             _ => AuthError::GenericError(String::from("Some database error occured")),
         }
     }
 }
+
+fn messages(errors: &[FieldError]) -> String {
+    errors.iter().map(|error| error.message.as_str()).collect::<Vec<_>>().join(", ")
+}
+
+// What a unique constraint guards, in words fit for clients
+fn duplicate_message(constraint: Option<&str>) -> &'static str {
+    match constraint {
+        Some("users_email_key") | Some("users_normalized_email") => "That email address is already in use",
+        Some("confirmations_email_key") | Some("confirmations_normalized_email") => "A confirmation was already sent to that address",
+        Some("users_username_key") => "That username is taken",
+        Some("account_deletions_user_id_key") => "The account is already being deleted",
+        _ => "That value is already taken",
+    }
+}
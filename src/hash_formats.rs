@@ -0,0 +1,236 @@
+use aes::Aes256;
+use ctr::{
+    stream_cipher::{generic_array::GenericArray, NewStreamCipher, SyncStreamCipher},
+    Ctr128
+};
+use hmac::Hmac;
+use scrypt::ScryptParams;
+use sha2::Sha256;
+
+use crate::{errors::AuthError, utils::constant_time_eq, vars};
+
+
+// The kinds of hash `users.hash` can hold. Only Argon2 is ever written by the service,
+// the others come from importing users from other systems and are upgraded on sign-in.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HashFormat {
+    // $argon2id$v=19$m=4096,t=192,p=8$<salt>$<hash>
+    Argon2,
+    // $2b$12$<salt and hash>
+    Bcrypt,
+    // $pbkdf2-sha256$i=100000,l=32$<salt>$<hash>, passlib's $pbkdf2-sha256$100000$<salt>$<hash>
+    // or Django's pbkdf2_sha256$100000$<salt>$<hash>
+    Pbkdf2Sha256,
+    // $scrypt$ln=15,r=8,p=1$<salt>$<hash>
+    Scrypt,
+    // $firebase-scrypt$<salt>$<hash>, with the project's parameters in `FIREBASE_*`
+    FirebaseScrypt,
+}
+
+impl HashFormat {
+    pub fn detect(hash: &str) -> Option<Self> {
+        if hash.starts_with("$argon2") {
+            Some(HashFormat::Argon2)
+        } else if hash.starts_with("$2a$") || hash.starts_with("$2b$") || hash.starts_with("$2y$") {
+            Some(HashFormat::Bcrypt)
+        } else if hash.starts_with("$pbkdf2-sha256$") || hash.starts_with("pbkdf2_sha256$") {
+            Some(HashFormat::Pbkdf2Sha256)
+        } else if hash.starts_with("$scrypt$") {
+            Some(HashFormat::Scrypt)
+        } else if hash.starts_with("$firebase-scrypt$") {
+            Some(HashFormat::FirebaseScrypt)
+        } else {
+            None
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            HashFormat::Argon2 => "argon2",
+            HashFormat::Bcrypt => "bcrypt",
+            HashFormat::Pbkdf2Sha256 => "pbkdf2-sha256",
+            HashFormat::Scrypt => "scrypt",
+            HashFormat::FirebaseScrypt => "firebase-scrypt",
+        }
+    }
+}
+
+
+// Checks a password against a hash in any format but Argon2, which needs the service's keys
+pub fn verify_foreign(format: HashFormat, hash: &str, password: &str) -> Result<bool, AuthError> {
+    match format {
+        HashFormat::Argon2 => Err(AuthError::ProcessError(String::from("Argon2 hashes are verified with a key"))),
+        HashFormat::Bcrypt => bcrypt::verify(password, hash).map_err(|_| malformed(format)),
+        HashFormat::Pbkdf2Sha256 => verify_pbkdf2(hash, password),
+        HashFormat::Scrypt => verify_scrypt(hash, password),
+        HashFormat::FirebaseScrypt => verify_firebase_scrypt(hash, password),
+    }
+}
+
+
+fn verify_pbkdf2(hash: &str, password: &str) -> Result<bool, AuthError> {
+    let error = || malformed(HashFormat::Pbkdf2Sha256);
+    let parts: Vec<&str> = hash.split('$').collect();
+
+    let (rounds, salt, expected) = match parts.as_slice() {
+        // passlib writes the rounds bare, "$pbkdf2-sha256$29000$<salt>$<hash>"
+        ["", "pbkdf2-sha256", params, salt, expected] => {
+            let rounds = params.parse().ok().or_else(|| param(params, "i")).ok_or_else(error)?;
+
+            (rounds, decode(salt).ok_or_else(error)?, decode(expected).ok_or_else(error)?)
+        },
+        // Django keeps the salt as plain text
+        ["pbkdf2_sha256", rounds, salt, expected] => {
+            (rounds.parse().map_err(|_| error())?, salt.as_bytes().to_vec(), decode(expected).ok_or_else(error)?)
+        },
+        _ => return Err(error()),
+    };
+    let mut derived = vec![0u8; expected.len()];
+
+    pbkdf2::pbkdf2::<Hmac<Sha256>>(password.as_bytes(), &salt, rounds, &mut derived);
+
+    Ok(constant_time_eq(&derived, &expected))
+}
+
+fn verify_scrypt(hash: &str, password: &str) -> Result<bool, AuthError> {
+    let error = || malformed(HashFormat::Scrypt);
+    let parts: Vec<&str> = hash.split('$').collect();
+
+    let (params, salt, expected) = match parts.as_slice() {
+        ["", "scrypt", params, salt, expected] => {
+            (*params, decode(salt).ok_or_else(error)?, decode(expected).ok_or_else(error)?)
+        },
+        _ => return Err(error()),
+    };
+    let log_n = param(params, "ln").ok_or_else(error)?;
+    let params = ScryptParams::new(log_n as u8, param(params, "r").ok_or_else(error)?, param(params, "p").ok_or_else(error)?)
+        .map_err(|_| error())?;
+    let mut derived = vec![0u8; expected.len()];
+
+    scrypt::scrypt(password.as_bytes(), &salt, &params, &mut derived).map_err(|_| error())?;
+
+    Ok(constant_time_eq(&derived, &expected))
+}
+
+// Firebase derives a key with scrypt and uses it to AES-256-CTR encrypt the project's signer key
+fn verify_firebase_scrypt(hash: &str, password: &str) -> Result<bool, AuthError> {
+    let error = || malformed(HashFormat::FirebaseScrypt);
+    let signer_key = vars::firebase_signer_key()
+        .ok_or_else(|| AuthError::ProcessError(String::from("FIREBASE_SIGNER_KEY is not set")))?;
+    let parts: Vec<&str> = hash.split('$').collect();
+
+    let (mut salt, expected) = match parts.as_slice() {
+        ["", "firebase-scrypt", salt, expected] => {
+            (decode(salt).ok_or_else(error)?, decode(expected).ok_or_else(error)?)
+        },
+        _ => return Err(error()),
+    };
+    let signer_key = decode(&signer_key).ok_or_else(error)?;
+
+    salt.extend(decode(&vars::firebase_salt_separator()).ok_or_else(error)?);
+
+    let params = ScryptParams::new(vars::firebase_mem_cost(), vars::firebase_rounds(), 1).map_err(|_| error())?;
+    let mut derived = [0u8; 64];
+
+    scrypt::scrypt(password.as_bytes(), &salt, &params, &mut derived).map_err(|_| error())?;
+
+    let mut encrypted = signer_key;
+    let mut cipher = Ctr128::<Aes256>::new(GenericArray::from_slice(&derived[..32]), &GenericArray::default());
+
+    cipher.apply_keystream(&mut encrypted);
+
+    Ok(constant_time_eq(&encrypted, &expected))
+}
+
+// "i=100000,l=32" has i = 100000
+fn param(params: &str, name: &str) -> Option<u32> {
+    params
+        .split(',')
+        .filter_map(|pair| {
+            let mut pair = pair.splitn(2, '=');
+
+            Some((pair.next()?, pair.next()?))
+        })
+        .find(|(key, _)| *key == name)
+        .and_then(|(_, value)| value.parse().ok())
+}
+
+// Standard base64, padded or not, or passlib's variant which uses '.' for '+'
+fn decode(value: &str) -> Option<Vec<u8>> {
+    base64::decode_config(value.trim_end_matches('=').replace('.', "+"), base64::STANDARD_NO_PAD).ok()
+}
+
+fn malformed(format: HashFormat) -> AuthError {
+    AuthError::ProcessError(format!("Malformed {} hash", format.name()))
+}
+
+
+// Known answers published with each algorithm, so a format that stops verifying them is caught
+// before imported users are locked out
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // crypt_blowfish's test vectors
+    const BCRYPT: &str = "$2a$05$CCCCCCCCCCCCCCCCCCCCC.E5YPO9kmyuRGyh0XouQYb4YMJKvyOeW";
+    // RFC 7914 section 11, P = "passwd", S = "salt", c = 1
+    const PBKDF2: &str = "$pbkdf2-sha256$i=1,l=64$c2FsdA$VawEblbjCJ/sFpHCJUS2BflBhSFt3gRl5oudV8INrLxJypzM8Xm2RZkWZLOdd+8xfHG4RbHjC9UJESBB06GXgw";
+    // written as passlib writes it, rounds bare and '.' for '+'
+    const PASSLIB_PBKDF2: &str = "$pbkdf2-sha256$1$c2FsdA$VawEblbjCJ/sFpHCJUS2BflBhSFt3gRl5oudV8INrLxJypzM8Xm2RZkWZLOdd.8xfHG4RbHjC9UJESBB06GXgw";
+    const DJANGO_PBKDF2: &str = "pbkdf2_sha256$1$salt$VawEblbjCJ/sFpHCJUS2BflBhSFt3gRl5oudV8INrLxJypzM8Xm2RZkWZLOdd+8xfHG4RbHjC9UJESBB06GXgw==";
+    // RFC 7914 section 12, P = "password", S = "NaCl", N = 1024, r = 8, p = 16
+    const SCRYPT: &str = "$scrypt$ln=10,r=8,p=16$TmFDbA$/bq+HJ00cgB4VucZDQHp/nxq18vII3gw53N2Y0s3MWIurzDZLiKjiG/xCSedmDDaxyevuUqD7m2DYMvfoswGQA";
+    // The sample project in github.com/firebase/scrypt
+    const FIREBASE_SCRYPT: &str = "$firebase-scrypt$42xEC+ixf3L2lw==$lSrfV15cpx95/sZS2W9c9Kp6i/LVgQNDNC/qzrCnh1SAyZvqmZqAjTdn3aoItz+VHjoZilo78198JAdRuid5lQ==";
+
+    #[test]
+    fn bcrypt_verifies_its_known_answer() {
+        assert_eq!(HashFormat::detect(BCRYPT), Some(HashFormat::Bcrypt));
+        assert!(verify_foreign(HashFormat::Bcrypt, BCRYPT, "U*U").unwrap());
+        assert!(!verify_foreign(HashFormat::Bcrypt, BCRYPT, "U*V").unwrap());
+    }
+
+    #[test]
+    fn pbkdf2_verifies_its_known_answer() {
+        assert_eq!(HashFormat::detect(PBKDF2), Some(HashFormat::Pbkdf2Sha256));
+        assert!(verify_foreign(HashFormat::Pbkdf2Sha256, PBKDF2, "passwd").unwrap());
+        assert!(!verify_foreign(HashFormat::Pbkdf2Sha256, PBKDF2, "password").unwrap());
+    }
+
+    #[test]
+    fn passlib_pbkdf2_verifies_its_known_answer() {
+        assert_eq!(HashFormat::detect(PASSLIB_PBKDF2), Some(HashFormat::Pbkdf2Sha256));
+        assert!(verify_foreign(HashFormat::Pbkdf2Sha256, PASSLIB_PBKDF2, "passwd").unwrap());
+        assert!(!verify_foreign(HashFormat::Pbkdf2Sha256, PASSLIB_PBKDF2, "password").unwrap());
+    }
+
+    #[test]
+    fn django_pbkdf2_verifies_its_known_answer() {
+        assert_eq!(HashFormat::detect(DJANGO_PBKDF2), Some(HashFormat::Pbkdf2Sha256));
+        assert!(verify_foreign(HashFormat::Pbkdf2Sha256, DJANGO_PBKDF2, "passwd").unwrap());
+        assert!(!verify_foreign(HashFormat::Pbkdf2Sha256, DJANGO_PBKDF2, "password").unwrap());
+    }
+
+    #[test]
+    fn scrypt_verifies_its_known_answer() {
+        assert_eq!(HashFormat::detect(SCRYPT), Some(HashFormat::Scrypt));
+        assert!(verify_foreign(HashFormat::Scrypt, SCRYPT, "password").unwrap());
+        assert!(!verify_foreign(HashFormat::Scrypt, SCRYPT, "passwd").unwrap());
+    }
+
+    #[test]
+    fn firebase_scrypt_verifies_its_known_answer() {
+        // the only test reading FIREBASE_*, so setting them can't race another
+        std::env::set_var(
+            "FIREBASE_SIGNER_KEY",
+            "jxspr8Ki0RYycVU8zykbdLGjFQ3McFUH0uiiTvC8pVMXAn210wjLNmdZJzxUECKbm0QsEmYUSDzZvpjeJ9WmXA=="
+        );
+        std::env::set_var("FIREBASE_SALT_SEPARATOR", "Bw==");
+        std::env::set_var("FIREBASE_ROUNDS", "8");
+        std::env::set_var("FIREBASE_MEM_COST", "14");
+
+        assert_eq!(HashFormat::detect(FIREBASE_SCRYPT), Some(HashFormat::FirebaseScrypt));
+        assert!(verify_foreign(HashFormat::FirebaseScrypt, FIREBASE_SCRYPT, "user1password").unwrap());
+        assert!(!verify_foreign(HashFormat::FirebaseScrypt, FIREBASE_SCRYPT, "user2password").unwrap());
+    }
+}
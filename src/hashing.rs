@@ -0,0 +1,209 @@
+use std::{collections::BTreeMap, fmt};
+
+use argonautica::{config::Variant, Hasher, Verifier};
+use lazy_static::lazy_static;
+use serde::Serialize;
+
+use crate::{
+    errors::AuthError,
+    hash_formats::{verify_foreign, HashFormat},
+    metrics,
+    vars
+};
+
+lazy_static! {
+    // Made once with the parameters and key in use at startup
+    static ref DUMMY_HASH: Option<PasswordHash> = hash_password("not anyone's password").ok();
+}
+
+
+// Argon2 cost parameters, as configured with the `ARGON2_*` variables or read off a stored hash
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct HashParams {
+    pub variant: Variant,
+    pub memory_kib: u32,
+    pub iterations: u32,
+    pub lanes: u32,
+}
+
+// A hash and the id of the secret key it was made with
+#[derive(Clone, Debug)]
+pub struct PasswordHash {
+    pub hash: String,
+    pub key_id: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct HashReport {
+    pub current: String,
+    pub current_key_id: String,
+    pub total: usize,
+    pub outdated: usize,
+    pub by_params: BTreeMap<String, usize>,
+    pub by_key_id: BTreeMap<String, usize>,
+}
+
+impl HashParams {
+    pub fn current() -> Self {
+        HashParams {
+            variant: vars::argon2_variant(),
+            memory_kib: vars::argon2_memory_kib(),
+            iterations: vars::argon2_iterations(),
+            lanes: vars::argon2_lanes(),
+        }
+    }
+
+    // Parses "$argon2id$v=19$m=4096,t=192,p=8$<salt>$<hash>"
+    pub fn from_hash(hash: &str) -> Option<Self> {
+        let mut parts = hash.split('$').skip(1);
+        let variant = match parts.next()? {
+            "argon2d" => Variant::Argon2d,
+            "argon2i" => Variant::Argon2i,
+            "argon2id" => Variant::Argon2id,
+            _ => return None,
+        };
+        let mut params = parts.find(|part| part.starts_with("m="))?.split(',');
+        let mut value = |key: &str| params.next()?.strip_prefix(key)?.parse::<u32>().ok();
+
+        Some(HashParams {
+            variant,
+            memory_kib: value("m=")?,
+            iterations: value("t=")?,
+            lanes: value("p=")?,
+        })
+    }
+
+    // Cheaper to attack than `other`: a different variant or less of any cost
+    pub fn is_weaker_than(&self, other: &HashParams) -> bool {
+        self.variant != other.variant
+            || self.memory_kib < other.memory_kib
+            || self.iterations < other.iterations
+            || self.lanes < other.lanes
+    }
+}
+
+impl fmt::Display for HashParams {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} m={},t={},p={}", self.variant.as_str(), self.memory_kib, self.iterations, self.lanes)
+    }
+}
+
+
+// Fails when a key is missing, duplicated, or the insecure default is used in production
+pub fn check_keys() -> Result<(), AuthError> {
+    let keys = vars::secret_keys();
+
+    if keys.is_empty() {
+        return Err(AuthError::ProcessError(String::from("No password hashing keys are configured")));
+    }
+
+    for (n, (id, _)) in keys.iter().enumerate() {
+        if keys[..n].iter().any(|(other, _)| other == id) {
+            return Err(AuthError::ProcessError(format!("Password hashing key {} is configured twice", id)));
+        }
+    }
+
+    if vars::is_production() && keys.iter().any(|(_, secret)| *secret == "0123".repeat(8)) {
+        return Err(AuthError::ProcessError(String::from(
+            "Refusing to use the default SECRET_KEY in production, set SECRET_KEY or SECRET_KEYS"
+        )));
+    }
+
+    Ok(())
+}
+
+pub fn current_key_id() -> String {
+    vars::secret_keys().swap_remove(0).0
+}
+
+// Hashes with the current parameters and key
+pub fn hash_password(password: &str) -> Result<PasswordHash, AuthError> {
+    let _timer = metrics::PASSWORD_HASH_SECONDS.with_label_values(&["hash"]).start_timer();
+    let params = HashParams::current();
+    let (key_id, secret) = vars::secret_keys().swap_remove(0);
+
+    let hash = Hasher::default()
+        .configure_variant(params.variant)
+        .configure_memory_size(params.memory_kib)
+        .configure_iterations(params.iterations)
+        .configure_lanes(params.lanes)
+        .configure_threads(params.lanes)
+        .with_password(password)
+        .with_secret_key(secret.as_str())
+        .hash()
+        .map_err(|_| AuthError::AuthenticationError(String::from("Could not hash password")))?;
+
+    Ok(PasswordHash { hash, key_id })
+}
+
+// Verifies Argon2 hashes with the key they were made with, current or retired,
+// and imported hashes according to their format
+pub fn verify(hash: &str, key_id: &str, password: &str) -> Result<bool, AuthError> {
+    let _timer = metrics::PASSWORD_HASH_SECONDS.with_label_values(&["verify"]).start_timer();
+
+    match HashFormat::detect(hash) {
+        Some(HashFormat::Argon2) => {},
+        Some(format) => return verify_foreign(format, hash, password),
+        None => return Err(AuthError::ProcessError(String::from("Unrecognised password hash"))),
+    }
+
+    let secret = vars::secret_keys()
+        .into_iter()
+        .find(|(id, _)| id == key_id)
+        .map(|(_, secret)| secret)
+        .ok_or_else(|| AuthError::ProcessError(format!("Unknown password hashing key {}", key_id)))?;
+
+    Verifier::default()
+        .with_hash(hash)
+        .with_password(password)
+        .with_secret_key(secret.as_str())
+        .verify()
+        .map_err(|_| AuthError::AuthenticationError(String::from("Could not verify password")))
+}
+
+// Does the work of verifying a password for a login nobody has, so an unknown login takes
+// as long to turn away as a wrong password
+pub fn verify_dummy(password: &str) {
+    if let Some(PasswordHash { hash, key_id }) = DUMMY_HASH.as_ref() {
+        let _ = verify(hash, key_id, password);
+    }
+}
+
+// Imported hashes, and Argon2 hashes made with a retired key or parameters that are weaker
+// or can't be read, are outdated
+pub fn needs_rehash(hash: &str, key_id: &str) -> bool {
+    key_id != current_key_id()
+        || HashParams::from_hash(hash).map_or(true, |params| params.is_weaker_than(&HashParams::current()))
+}
+
+pub fn report<'a, I: IntoIterator<Item = (&'a str, &'a str)>>(hashes: I) -> HashReport {
+    let current = HashParams::current();
+    let current_key_id = current_key_id();
+    let mut report = HashReport {
+        current: current.to_string(),
+        current_key_id: current_key_id.clone(),
+        total: 0,
+        outdated: 0,
+        by_params: BTreeMap::new(),
+        by_key_id: BTreeMap::new(),
+    };
+
+    for (hash, key_id) in hashes {
+        let params = HashParams::from_hash(hash);
+
+        report.total += 1;
+
+        if key_id != current_key_id || params.map_or(true, |params| params.is_weaker_than(&current)) {
+            report.outdated += 1;
+        }
+
+        let label = params.map(|params| params.to_string()).unwrap_or_else(|| {
+            HashFormat::detect(hash).map_or_else(|| String::from("unknown"), |format| String::from(format.name()))
+        });
+
+        *report.by_params.entry(label).or_insert(0) += 1;
+        *report.by_key_id.entry(String::from(key_id)).or_insert(0) += 1;
+    }
+
+    report
+}
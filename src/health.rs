@@ -0,0 +1,194 @@
+use std::{
+    collections::BTreeMap,
+    io::{Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc
+    },
+    time::{Duration, Instant}
+};
+
+use actix_web::{error::BlockingError, web, HttpResponse};
+use diesel::prelude::*;
+use native_tls::TlsConnector;
+use serde::Serialize;
+use tracing::warn;
+
+use crate::{models::Pool, vars};
+
+
+// Why a check failed is only logged, the endpoint is unauthenticated
+#[derive(Debug, Serialize)]
+pub struct CheckResult {
+    pub status: &'static str,
+    pub latency_ms: u128,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Readiness {
+    pub status: &'static str,
+    pub checks: BTreeMap<&'static str, CheckResult>,
+}
+
+// Whether the service should receive traffic. Cleared when shutdown begins.
+#[derive(Clone)]
+pub struct Health {
+    ready: Arc<AtomicBool>,
+}
+
+impl Health {
+    pub fn new() -> Self {
+        Health { ready: Arc::new(AtomicBool::new(true)) }
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        !self.ready.load(Ordering::SeqCst)
+    }
+
+    // Makes /readyz fail so the orchestrator stops routing to us while requests drain
+    pub fn begin_shutdown(&self) {
+        self.ready.store(false, Ordering::SeqCst);
+    }
+}
+
+impl Default for Health {
+    fn default() -> Self {
+        Health::new()
+    }
+}
+
+
+pub async fn liveness() -> HttpResponse {
+    HttpResponse::Ok().json(serde_json::json!({ "status": "up" }))
+}
+
+pub async fn readiness(health: web::Data<Health>, pool: web::Data<Pool>) -> HttpResponse {
+    let mut checks = BTreeMap::new();
+
+    if health.is_shutting_down() {
+        let readiness = Readiness { status: "shutting_down", checks };
+
+        return HttpResponse::ServiceUnavailable().json(readiness);
+    }
+
+    let pool = pool.get_ref().clone();
+    let (postgres, redis, smtp) = futures::future::join3(
+        check("postgres", move || check_postgres(&pool)),
+        check("redis", check_redis),
+        async {
+            if vars::health_check_smtp() {
+                Some(check("smtp", check_smtp).await)
+            } else {
+                None
+            }
+        }
+    )
+    .await;
+
+    checks.insert("postgres", postgres);
+    checks.insert("redis", redis);
+
+    if let Some(smtp) = smtp {
+        checks.insert("smtp", smtp);
+    }
+
+    if checks.values().all(|result| result.status == "up") {
+        HttpResponse::Ok().json(Readiness { status: "ready", checks })
+    } else {
+        HttpResponse::ServiceUnavailable().json(Readiness { status: "not_ready", checks })
+    }
+}
+
+
+async fn check<F>(name: &'static str, probe: F) -> CheckResult
+where
+    F: FnOnce() -> Result<(), String> + Send + 'static,
+{
+    let started = Instant::now();
+    let result = web::block(probe).await;
+    let latency_ms = started.elapsed().as_millis();
+    let error = match result {
+        Ok(()) => return CheckResult { status: "up", latency_ms },
+        Err(BlockingError::Error(err)) => err,
+        Err(BlockingError::Canceled) => String::from("Check was canceled"),
+    };
+
+    warn!(check = name, latency_ms = latency_ms as u64, "Readiness check failed: {}", error);
+
+    CheckResult { status: "down", latency_ms }
+}
+
+fn check_postgres(pool: &Pool) -> Result<(), String> {
+    let conn = pool.get_timeout(timeout()).map_err(|err| err.to_string())?;
+
+    diesel::sql_query("SELECT 1").execute(&conn).map(|_| ()).map_err(|err| err.to_string())
+}
+
+fn check_redis() -> Result<(), String> {
+    let mut stream = connect(&vars::redis_address())?;
+    let mut reply = [0u8; 7];
+
+    // without it a server that requires a password answers PING with an error
+    if let Some(password) = vars::redis_password() {
+        let auth = format!("*2\r\n$4\r\nAUTH\r\n${}\r\n{}\r\n", password.len(), password);
+        let mut reply = [0u8; 5];
+
+        stream.write_all(auth.as_bytes()).map_err(|err| err.to_string())?;
+        stream.read_exact(&mut reply).map_err(|err| err.to_string())?;
+
+        if &reply != b"+OK\r\n" {
+            return Err(String::from("Redis refused the password"));
+        }
+    }
+
+    stream.write_all(b"PING\r\n").map_err(|err| err.to_string())?;
+    stream.read_exact(&mut reply).map_err(|err| err.to_string())?;
+
+    if &reply == b"+PONG\r\n" {
+        Ok(())
+    } else {
+        Err(String::from("Unexpected reply to PING"))
+    }
+}
+
+fn check_smtp() -> Result<(), String> {
+    let host = vars::smtp_host();
+    let port = vars::smtp_port();
+    let mut stream = connect(&format!("{}:{}", host, port))?;
+    let mut greeting = [0u8; 3];
+
+    // servers on the SMTPS port expect a TLS handshake before they greet
+    if port == 465 {
+        let connector = TlsConnector::new().map_err(|err| err.to_string())?;
+        let mut stream = connector.connect(&host, stream).map_err(|err| err.to_string())?;
+
+        stream.read_exact(&mut greeting).map_err(|err| err.to_string())?;
+    } else {
+        stream.read_exact(&mut greeting).map_err(|err| err.to_string())?;
+    }
+
+    if &greeting == b"220" {
+        Ok(())
+    } else {
+        Err(String::from("Unexpected SMTP greeting"))
+    }
+}
+
+fn connect(address: &str) -> Result<TcpStream, String> {
+    let address = address
+        .to_socket_addrs()
+        .map_err(|err| err.to_string())?
+        .next()
+        .ok_or_else(|| format!("Could not resolve {}", address))?;
+    let stream = TcpStream::connect_timeout(&address, timeout()).map_err(|err| err.to_string())?;
+
+    stream.set_read_timeout(Some(timeout())).map_err(|err| err.to_string())?;
+    stream.set_write_timeout(Some(timeout())).map_err(|err| err.to_string())?;
+
+    Ok(stream)
+}
+
+fn timeout() -> Duration {
+    Duration::from_millis(vars::health_check_timeout_ms())
+}
@@ -0,0 +1,43 @@
+#[macro_use]
+extern crate diesel;
+#[macro_use]
+extern crate diesel_migrations;
+extern crate serde_json;
+extern crate lettre;
+extern crate native_tls;
+
+pub mod account_handler;
+pub mod admin_handler;
+pub mod auth_handler;
+pub mod credentials;
+pub mod db;
+pub mod email_handler;
+pub mod email_service;
+pub mod emails;
+pub mod errors;
+pub mod hash_formats;
+pub mod hashing;
+pub mod health;
+pub mod metrics;
+pub mod migrations;
+pub mod models;
+pub mod negotiation;
+pub mod openapi;
+pub mod password_handler;
+pub mod password_history;
+pub mod password_policy;
+pub mod register_handler;
+pub mod reset_handler;
+pub mod scheduler;
+pub mod schema;
+pub mod service;
+pub mod sessions;
+pub mod telemetry;
+pub mod templates;
+pub mod username_handler;
+pub mod usernames;
+pub mod utils;
+pub mod validation;
+pub mod vars;
+
+pub use service::{AuthService, AuthServiceBuilder};
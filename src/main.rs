@@ -1,19 +1,4 @@
-#[macro_use]
-extern crate diesel;
-extern crate serde_json;
-extern crate lettre;
-extern crate native_tls;
-
-mod auth_handler;
-mod email_service;
-mod errors;
-mod models;
-mod password_handler;
-mod register_handler;
-mod schema;
-mod templates;
-mod utils;
-mod vars;
+use auth_service::{db, email_service::SmtpMailer, emails, metrics, migrations, telemetry, vars, AuthService};
 
 
 #[actix_rt::main]
@@ -21,65 +6,118 @@ async fn main() -> std::io::Result<()> {
     use actix_cors::Cors;
     use actix_files::Files;
     use actix_redis::RedisSession;
-    use actix_web::{middleware, web, App, HttpServer};
-    use diesel::{
-        prelude::*, 
-        r2d2::{self, ConnectionManager}
-    };
+    use actix_web::{web, App, HttpServer};
 
-    std::env::set_var("RUST_LOG", "actix_web=info,actix_server=info");
-    env_logger::init();
+    telemetry::init();
 
     // create a database connection pool
-    let manager = ConnectionManager::<PgConnection>::new(vars::database_url());
-    let pool: models::Pool = r2d2::Pool::builder()
-        .build(manager)
-        .expect("Failed to create a database connection pool.");
+    let pool = db::connect(&vars::database_url()).expect("Failed to create a database connection pool.");
+
+    let migrate_only = std::env::args().any(|arg| arg == "--migrate-only");
+
+    if migrate_only || vars::run_migrations() {
+        // not a pooled connection, whose statement timeout would cancel the wait for the migration lock
+        migrations::run(&db::connect_unpooled(&vars::database_url()).expect("Failed to connect to the database."))
+            .expect("Failed to run database migrations.");
+
+        if migrate_only {
+            return Ok(());
+        }
+    }
+
+    // when migrations were applied some other way, e.g. with the diesel CLI, nobody could be found by email
+    let backfilled = emails::is_backfilled(&pool.get().expect("Failed to get a database connection."))
+        .expect("Failed to check for normalized emails.");
+
+    assert!(backfilled, "Normalized emails haven't been filled in, run `auth-admin migrate`.");
+
+    let auth = AuthService::builder()
+        .store(pool.clone())
+        .mailer(SmtpMailer)
+        .build();
+
+    // purge expired records in the background
+    auth.scheduler().start();
+
+    // Serve metrics on their own port, e.g. one that's only reachable internally
+    let metrics_server = vars::metrics_port().map(|port| {
+        let pool = pool.clone();
+
+        HttpServer::new(move || {
+            App::new()
+                .data(pool.clone())
+                .route("/metrics", web::get().to(metrics::metrics))
+        })
+        .bind(format!("{}:{}", vars::domain(), port))
+        .map(|server| server.disable_signals().run())
+    })
+    .transpose()?;
+    let health = auth.health().clone();
 
     // Start http server
-    HttpServer::new(move || {
+    let server = HttpServer::new(move || {
         App::new()
-            .data(pool.clone())
-            // enable logger
-            .wrap(middleware::Logger::default())
+            .wrap(metrics::RequestMetrics)
+            // a span and request id for every request
+            .wrap(telemetry::RequestTracing)
             // Enable sessions
-            .wrap(RedisSession::new("127.0.0.1:6379", &[0; 32]))
+            .wrap({
+                let session = RedisSession::new(vars::redis_address(), &vars::session_key());
+
+                match vars::session_cookie_domain() {
+                    Some(domain) => session.cookie_domain(&domain),
+                    None => session,
+                }
+            })
             .wrap(
                 Cors::new()
                     .allowed_methods(vec!["GET", "POST", "DELETE", "OPTIONS"])
                     .max_age(3600)
                     .finish())
             .service(Files::new("/assets", "./templates/assets"))
-            // Routes
-            .service(
-                web::scope("/")
-                    .service(
-                        web::resource("/register")
-                            .route(web::get().to(register_handler::show_confirmation_form))
-                            .route(web::post().to(register_handler::send_confirmation)),
-                    )
-                    .service(
-                        web::resource("/register/{path_id}")
-                            .route(web::get().to(password_handler::show_password_form))
-                            .route(web::post().to(password_handler::create_account)),
-                    )
-                    .route("/register2/{path_id}", web::post().to(password_handler::create_account_for_browser))
-                    .route("/register2", web::post().to(register_handler::send_confirmation_for_browser))
-                    .route("/me", web::get().to(auth_handler::me))
-                    .service(
-                        web::resource("/signout")
-                            .route(web::get().to(auth_handler::sign_out))
-                            .route(web::delete().to(auth_handler::sign_out)),
-                    )
-                    .service(
-                        web::resource("/signin")
-                            .route(web::get().to(auth_handler::show_sign_in_form))
-                            .route(web::post().to(auth_handler::sign_in)),
-                    )
-                    .route("/signin2", web::post().to(auth_handler::sign_in_for_browser)),
-            )
+            .configure(|cfg| auth.configure(cfg))
     })
     .bind(format!("{}:{}", vars::domain(), vars::port()))?
-    .run()
-    .await
+    .disable_signals()
+    .run();
+
+    // On SIGTERM/SIGINT fail readiness first, so traffic moves away before we stop accepting it
+    {
+        let server = server.clone();
+        let metrics_server = metrics_server.clone();
+
+        actix_rt::spawn(async move {
+            wait_for_shutdown_signal().await;
+            tracing::info!("Shutting down");
+
+            health.begin_shutdown();
+            actix_rt::time::delay_for(std::time::Duration::from_secs(vars::shutdown_drain_secs())).await;
+            server.stop(true).await;
+
+            if let Some(metrics_server) = metrics_server {
+                metrics_server.stop(true).await;
+            }
+        });
+    }
+
+    match metrics_server {
+        Some(metrics_server) => futures::future::try_join(server, metrics_server).await.map(|_| ()),
+        None => server.await,
+    }
+}
+
+
+#[cfg(unix)]
+async fn wait_for_shutdown_signal() {
+    use actix_rt::signal::unix::{signal, SignalKind};
+
+    let mut terminate = signal(SignalKind::terminate()).expect("Failed to listen for SIGTERM");
+    let mut interrupt = signal(SignalKind::interrupt()).expect("Failed to listen for SIGINT");
+
+    futures::future::select(Box::pin(terminate.recv()), Box::pin(interrupt.recv())).await;
+}
+
+#[cfg(not(unix))]
+async fn wait_for_shutdown_signal() {
+    let _ = actix_rt::signal::ctrl_c().await;
 }
@@ -0,0 +1,247 @@
+use std::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Instant
+};
+
+use actix_service::{Service, Transform};
+use actix_web::{
+    dev::{ResourceDef, ServiceRequest, ServiceResponse},
+    web,
+    Error,
+    HttpRequest,
+    HttpResponse
+};
+use futures::future::{ok, Ready};
+use lazy_static::lazy_static;
+use prometheus::{
+    register_histogram,
+    register_histogram_vec,
+    register_int_counter,
+    register_int_counter_vec,
+    register_int_gauge,
+    register_int_gauge_vec,
+    Encoder,
+    Histogram,
+    HistogramVec,
+    IntCounter,
+    IntCounterVec,
+    IntGauge,
+    IntGaugeVec,
+    TextEncoder
+};
+
+use crate::{errors::AuthError, models::Pool, service::ROUTES, utils::authorize_bearer, vars};
+
+// The methods that get their own label, anything else a client sends is counted as "OTHER"
+const METHODS: [&str; 7] = ["GET", "HEAD", "POST", "PUT", "DELETE", "PATCH", "OPTIONS"];
+
+
+lazy_static! {
+    static ref ROUTE_DEFS: Vec<(&'static str, ResourceDef)> =
+        ROUTES.iter().map(|route| (*route, ResourceDef::new(*route))).collect();
+
+    pub static ref HTTP_REQUESTS: IntCounterVec = register_int_counter_vec!(
+        "auth_http_requests_total",
+        "HTTP requests by method, path and status",
+        &["method", "path", "status"]
+    ).unwrap();
+
+    pub static ref HTTP_REQUEST_SECONDS: HistogramVec = register_histogram_vec!(
+        "auth_http_request_duration_seconds",
+        "HTTP request latency by method and path",
+        &["method", "path"]
+    ).unwrap();
+
+    pub static ref SIGN_INS: IntCounterVec = register_int_counter_vec!(
+        "auth_sign_ins_total",
+        "Sign-in attempts by outcome",
+        &["outcome"]
+    ).unwrap();
+
+    pub static ref REGISTRATIONS: IntCounter = register_int_counter!(
+        "auth_registrations_total",
+        "Accounts created from a registration confirmation"
+    ).unwrap();
+
+    pub static ref EMAILS: IntCounterVec = register_int_counter_vec!(
+        "auth_emails_total",
+        "Emails by kind and outcome",
+        &["kind", "outcome"]
+    ).unwrap();
+
+    pub static ref PASSWORD_HASH_SECONDS: HistogramVec = register_histogram_vec!(
+        "auth_password_hash_duration_seconds",
+        "Time spent hashing and verifying passwords",
+        &["operation"],
+        vec![0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0]
+    ).unwrap();
+
+    pub static ref CREDENTIAL_QUEUE_SECONDS: Histogram = register_histogram!(
+        "auth_credential_queue_duration_seconds",
+        "Time credential jobs wait for a worker",
+        vec![0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0]
+    ).unwrap();
+
+    pub static ref CREDENTIAL_QUEUE_DEPTH: IntGauge = register_int_gauge!(
+        "auth_credential_queue_depth",
+        "Credential jobs waiting for a worker"
+    ).unwrap();
+
+    pub static ref CREDENTIAL_REJECTED: IntCounter = register_int_counter!(
+        "auth_credential_rejected_total",
+        "Credential jobs turned away because the queue was full"
+    ).unwrap();
+
+    pub static ref DB_POOL_CONNECTIONS: IntGaugeVec = register_int_gauge_vec!(
+        "auth_db_pool_connections",
+        "Database pool connections by state",
+        &["state"]
+    ).unwrap();
+
+    pub static ref DB_POOL_TIMEOUTS: IntCounter = register_int_counter!(
+        "auth_db_pool_timeouts_total",
+        "Requests that gave up waiting for a database connection"
+    ).unwrap();
+
+    pub static ref SESSION_ERRORS: IntCounter = register_int_counter!(
+        "auth_session_errors_total",
+        "Failures reading or writing the session"
+    ).unwrap();
+
+    pub static ref MAINTENANCE_RUNS: IntCounterVec = register_int_counter_vec!(
+        "auth_maintenance_runs_total",
+        "Maintenance runs by outcome",
+        &["outcome"]
+    ).unwrap();
+
+    pub static ref MAINTENANCE_REMOVED: IntCounterVec = register_int_counter_vec!(
+        "auth_maintenance_removed_total",
+        "Records removed by maintenance runs",
+        &["kind"]
+    ).unwrap();
+}
+
+
+pub async fn metrics(req: HttpRequest, pool: web::Data<Pool>) -> Result<HttpResponse, AuthError> {
+    if let Some(token) = vars::metrics_token() {
+        authorize_bearer(&req, &token)?;
+    }
+
+    let state = pool.state();
+    DB_POOL_CONNECTIONS.with_label_values(&["idle"]).set(state.idle_connections as i64);
+    DB_POOL_CONNECTIONS.with_label_values(&["active"]).set((state.connections - state.idle_connections) as i64);
+    DB_POOL_CONNECTIONS.with_label_values(&["max"]).set(pool.max_size() as i64);
+
+    let encoder = TextEncoder::new();
+    let mut buffer = vec![];
+
+    encoder
+        .encode(&prometheus::gather(), &mut buffer)
+        .map_err(|_| AuthError::ProcessError(String::from("Could not encode metrics")))?;
+
+    Ok(HttpResponse::Ok().content_type(encoder.format_type()).body(buffer))
+}
+
+// Middleware counting and timing every request
+pub struct RequestMetrics;
+
+impl<S, B> Transform<S> for RequestMetrics
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = RequestMetricsMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(RequestMetricsMiddleware { service })
+    }
+}
+
+pub struct RequestMetricsMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service for RequestMetricsMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let started = Instant::now();
+        let method = method_label(req.method().as_str());
+        let path = route_label(req.path());
+        let fut = self.service.call(req);
+
+        Box::pin(async move {
+            let res = fut.await?;
+
+            HTTP_REQUESTS.with_label_values(&[method, path, res.status().as_str()]).inc();
+            HTTP_REQUEST_SECONDS
+                .with_label_values(&[method, path])
+                .observe(started.elapsed().as_secs_f64());
+
+            Ok(res)
+        })
+    }
+}
+
+
+// "/me/email/{path_id}/revert" rather than the path, so made up paths and ids don't each
+// get a series
+fn route_label(path: &str) -> &'static str {
+    ROUTE_DEFS
+        .iter()
+        .find(|(_, def)| def.is_match(path))
+        .map_or("unmatched", |(route, _)| *route)
+}
+
+fn method_label(method: &str) -> &'static str {
+    METHODS.iter().find(|known| **known == method).map_or("OTHER", |known| *known)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn paths_are_labelled_by_route() {
+        assert_eq!(route_label("/me"), "/me");
+        assert_eq!(route_label("/register/resend"), "/register/resend");
+        assert_eq!(route_label("/register/3fa85f64-5717-4562-b3fc-2c963f66afa6"), "/register/{path_id}");
+        assert_eq!(route_label("/me/email/anything/revert"), "/me/email/{path_id}/revert");
+        assert_eq!(route_label("/api/v1/me"), "/api/v1/me");
+    }
+
+    #[test]
+    fn unknown_paths_share_a_label() {
+        assert_eq!(route_label("/wp-login.php"), "unmatched");
+        assert_eq!(route_label("/me/email/a/b/c"), "unmatched");
+    }
+
+    #[test]
+    fn unknown_methods_share_a_label() {
+        assert_eq!(method_label("GET"), "GET");
+        assert_eq!(method_label("DELETE"), "DELETE");
+        assert_eq!(method_label("BREW"), "OTHER");
+        assert_eq!(method_label("get"), "OTHER");
+    }
+}
@@ -0,0 +1,56 @@
+use diesel::{pg::PgConnection, prelude::*, sql_types::BigInt};
+use diesel_migrations::MigrationConnection;
+use serde::Serialize;
+use tracing::info;
+
+use crate::{emails, errors::AuthError};
+
+embed_migrations!();
+
+include!(concat!(env!("OUT_DIR"), "/migration_versions.rs"));
+
+// Arbitrary, but shared by every replica so only one of them migrates at a time
+const MIGRATION_LOCK_ID: i64 = 4_861_020_417;
+
+
+#[derive(Debug, Serialize)]
+pub struct MigrationStatus {
+    pub version: String,
+    pub applied: bool,
+}
+
+// Applies every pending embedded migration while holding a Postgres advisory lock. Give it
+// a connection from `db::connect_unpooled`, a statement timeout would cancel the wait for
+// the lock.
+pub fn run(conn: &PgConnection) -> Result<(), AuthError> {
+    diesel::sql_query("SELECT pg_advisory_lock($1)")
+        .bind::<BigInt, _>(MIGRATION_LOCK_ID)
+        .execute(conn)?;
+
+    let mut output = Vec::new();
+    let result = embedded_migrations::run_with_output(conn, &mut output)
+        .map_err(|err| AuthError::ProcessError(format!("Could not run migrations: {}", err)))
+        // data the SQL migrations can't compute, under the same lock
+        .and_then(|_| emails::backfill(conn));
+
+    diesel::sql_query("SELECT pg_advisory_unlock($1)")
+        .bind::<BigInt, _>(MIGRATION_LOCK_ID)
+        .execute(conn)?;
+
+    for line in String::from_utf8_lossy(&output).lines() {
+        info!("{}", line);
+    }
+
+    result
+}
+
+pub fn status(conn: &PgConnection) -> Result<Vec<MigrationStatus>, AuthError> {
+    diesel_migrations::setup_database(conn)?;
+
+    let applied = conn.previously_run_migration_versions()?;
+
+    Ok(MIGRATION_VERSIONS
+        .iter()
+        .map(|version| MigrationStatus { version: version.to_string(), applied: applied.contains(*version) })
+        .collect())
+}
@@ -1,18 +1,30 @@
 use diesel::{r2d2::ConnectionManager, PgConnection};
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
-use super::schema::*;
+use super::{emails, hashing::PasswordHash, schema::*};
 
 // type alias to reduce verbosity
 pub type Pool = r2d2::Pool<ConnectionManager<PgConnection>>;
 
-#[derive(Debug, Serialize, Deserialize, Queryable, Insertable)]
+#[derive(Debug, Serialize, Deserialize, Queryable, Insertable, JsonSchema)]
+#[table_name = "account_deletions"]
+pub struct AccountDeletion {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub scheduled_for: chrono::NaiveDateTime,
+    pub created_at: chrono::NaiveDateTime,
+}
+
+#[derive(Debug, Serialize, Deserialize, Queryable, Insertable, JsonSchema)]
 #[table_name = "confirmations"]
 pub struct Confirmation {
     pub id: Uuid,
     pub email: String,
     pub expires_at: chrono::NaiveDateTime,
+    pub sent_at: chrono::NaiveDateTime,
+    pub normalized_email: String,
 }
 
 #[derive(Debug, Serialize, Deserialize, Queryable, Insertable)]
@@ -22,39 +34,150 @@ pub struct User {
     pub email: String,
     pub hash: String,
     pub created_at: chrono::NaiveDateTime,
+    pub roles: Vec<String>,
+    pub disabled_at: Option<chrono::NaiveDateTime>,
+    pub key_id: String,
+    pub password_changed_at: chrono::NaiveDateTime,
+    pub normalized_email: String,
+    pub username: Option<String>,
+    pub username_changed_at: Option<chrono::NaiveDateTime>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Queryable, Insertable)]
+#[table_name = "password_history"]
+pub struct PasswordHistory {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub hash: String,
+    pub key_id: String,
+    pub created_at: chrono::NaiveDateTime,
+}
+
+#[derive(Debug, Serialize, Deserialize, Queryable, Insertable)]
+#[table_name = "password_resets"]
+pub struct PasswordReset {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub expires_at: chrono::NaiveDateTime,
+    pub created_at: chrono::NaiveDateTime,
+}
+
+#[derive(Debug, Serialize, Deserialize, Queryable, Insertable, JsonSchema)]
+#[table_name = "email_changes"]
+pub struct EmailChange {
+    pub id: Uuid,
+    pub revert_id: Uuid,
+    pub user_id: Uuid,
+    pub old_email: String,
+    pub new_email: String,
+    pub expires_at: chrono::NaiveDateTime,
+    pub confirmed_at: Option<chrono::NaiveDateTime>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
 pub struct SessionUser {
     pub id: Uuid,
     pub email: String,
+    #[serde(default)]
+    pub roles: Vec<String>,
+    #[serde(default)]
+    pub username: Option<String>,
 }
 
 // any type that implements Into<String> can be used to create a Confirmation
 impl<T> From<T> for Confirmation where
 T: Into<String> {
      fn from(email: T) -> Self {
+        let now = chrono::Local::now().naive_local();
+        let email = email.into();
+
         Confirmation {
             id: Uuid::new_v4(),
-            email: email.into(),
-            expires_at: chrono::Local::now().naive_local() + chrono::Duration::hours(24),
+            normalized_email: emails::normalize(&email),
+            email,
+            expires_at: now + chrono::Duration::hours(24),
+            sent_at: now,
         }
     }
 }
 
 impl From<User> for SessionUser {
-    fn from(User { email, id, .. }: User) -> Self {
-        SessionUser { email, id }
+    fn from(User { email, id, roles, username, .. }: User) -> Self {
+        SessionUser { email, id, roles, username }
+    }
+}
+
+impl AccountDeletion {
+    pub fn from(user: &SessionUser, grace_hours: i64) -> Self {
+        let now = chrono::Local::now().naive_local();
+
+        AccountDeletion {
+            id: Uuid::new_v4(),
+            user_id: user.id,
+            scheduled_for: now + chrono::Duration::hours(grace_hours),
+            created_at: now,
+        }
+    }
+}
+
+impl EmailChange {
+    pub fn from<T: Into<String>>(user: &SessionUser, new_email: T) -> Self {
+        EmailChange {
+            id: Uuid::new_v4(),
+            revert_id: Uuid::new_v4(),
+            user_id: user.id,
+            old_email: user.email.clone(),
+            new_email: new_email.into(),
+            expires_at: chrono::Local::now().naive_local() + chrono::Duration::hours(24),
+            confirmed_at: None,
+        }
     }
 }
 
 impl User {
-    pub fn from<S: Into<String>, T: Into<String>>(email: S, pwd: T) -> Self {
+    pub fn from<S: Into<String>>(email: S, PasswordHash { hash, key_id }: PasswordHash) -> Self {
+        let now = chrono::Local::now().naive_local();
+        let email = email.into();
+
         User {
             id: Uuid::new_v4(),
-            email: email.into(),
-            hash: pwd.into(),
+            normalized_email: emails::normalize(&email),
+            email,
+            hash,
+            created_at: now,
+            roles: vec![],
+            disabled_at: None,
+            key_id,
+            password_changed_at: now,
+            username: None,
+            username_changed_at: None,
+        }
+    }
+}
+
+impl PasswordReset {
+    // Reset links are good for an hour, they're as good as the password
+    pub fn from(user: &User) -> Self {
+        let now = chrono::Local::now().naive_local();
+
+        PasswordReset {
+            id: Uuid::new_v4(),
+            user_id: user.id,
+            expires_at: now + chrono::Duration::hours(1),
+            created_at: now,
+        }
+    }
+}
+
+impl PasswordHistory {
+    // The password a user is moving away from
+    pub fn from(user: &User) -> Self {
+        PasswordHistory {
+            id: Uuid::new_v4(),
+            user_id: user.id,
+            hash: user.hash.clone(),
+            key_id: user.key_id.clone(),
             created_at: chrono::Local::now().naive_local(),
         }
     }
-}
\ No newline at end of file
+}
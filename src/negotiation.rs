@@ -0,0 +1,202 @@
+use actix_web::{
+    dev,
+    http::{header::{ACCEPT, CONTENT_TYPE}, HeaderName},
+    web,
+    Error,
+    FromRequest,
+    HttpMessage,
+    HttpRequest
+};
+use futures::future::{FutureExt, LocalBoxFuture, TryFutureExt};
+use serde::de::DeserializeOwned;
+
+
+// The representations a handler can respond with
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Json,
+    Html,
+}
+
+// A JSON or urlencoded form body, read according to its `Content-Type`, so one handler
+// can serve API clients and HTML forms alike
+pub struct JsonOrForm<T>(pub T);
+
+impl Format {
+    fn media_type(self) -> (&'static str, &'static str) {
+        match self {
+            Format::Json => ("application", "json"),
+            Format::Html => ("text", "html"),
+        }
+    }
+}
+
+impl<T> JsonOrForm<T> {
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T: DeserializeOwned + 'static> FromRequest for JsonOrForm<T> {
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Error>>;
+    type Config = ();
+
+    fn from_request(req: &HttpRequest, payload: &mut dev::Payload) -> Self::Future {
+        if is_json_content(req) {
+            web::Json::<T>::from_request(req, payload).map_ok(|json| JsonOrForm(json.into_inner())).boxed_local()
+        } else {
+            web::Form::<T>::from_request(req, payload).map_ok(|form| JsonOrForm(form.into_inner())).boxed_local()
+        }
+    }
+}
+
+
+// Picks the response format from `Accept`, honouring q-values. Between formats with the
+// same q-value, the one named more specifically wins, so "application/json, */*" is JSON.
+// When `Accept` is missing or ranks both formats alike, e.g. "*/*", a JSON body marks an
+// API client and otherwise the route's default is used. A scope can replace the default
+// by putting a `Format` in the request extensions, as /api/v1 does.
+pub fn negotiate(req: &HttpRequest, default: Format) -> Format {
+    let default = req.extensions().get::<Format>().copied().unwrap_or(default);
+    let accept = header(req, ACCEPT);
+    let (json, json_specificity) = quality(accept, Format::Json);
+    let (html, html_specificity) = quality(accept, Format::Html);
+
+    if json > html {
+        Format::Json
+    } else if html > json {
+        Format::Html
+    } else if json > 0.0 && json_specificity > html_specificity {
+        Format::Json
+    } else if html > 0.0 && html_specificity > json_specificity {
+        Format::Html
+    } else if json > 0.0 && is_json_content(req) {
+        Format::Json
+    } else {
+        default
+    }
+}
+
+pub fn wants_json(req: &HttpRequest, default: Format) -> bool {
+    negotiate(req, default) == Format::Json
+}
+
+// "application/json", with or without parameters, and "+json" types like "application/problem+json"
+pub fn is_json_content(req: &HttpRequest) -> bool {
+    header(req, CONTENT_TYPE)
+        .and_then(|content_type| content_type.split(';').next())
+        .map(|media_type| media_type.trim().to_ascii_lowercase())
+        .map_or(false, |media_type| media_type == "application/json" || media_type.ends_with("+json"))
+}
+
+
+fn header(req: &HttpRequest, name: HeaderName) -> Option<&str> {
+    req.headers().get(name).and_then(|value| value.to_str().ok())
+}
+
+// The q-value of the most specific media range matching `format`, and how specific that
+// range is: "text/html;level=1" beats "text/html", which beats "text/*", which beats "*/*".
+// Without `Accept` anything goes, with no specificity.
+fn quality(accept: Option<&str>, format: Format) -> (f32, u32) {
+    let accept = match accept {
+        Some(accept) if !accept.trim().is_empty() => accept,
+        _ => return (1.0, 0),
+    };
+    let (main_type, sub_type) = format.media_type();
+
+    accept
+        .split(',')
+        .filter_map(|range| {
+            let mut parts = range.split(';').map(str::trim);
+            let media_type = parts.next()?.to_ascii_lowercase();
+            let mut media_type = media_type.splitn(2, '/');
+            let specificity = match (media_type.next()?, media_type.next()?) {
+                ("*", "*") => 1,
+                (main, "*") if main == main_type => 2,
+                (main, sub) if main == main_type && (sub == sub_type || sub.ends_with(&format!("+{}", sub_type))) => 3,
+                _ => return None,
+            };
+            let mut q = 1.0;
+            let mut params = 0;
+
+            for param in parts {
+                let mut pair = param.splitn(2, '=').map(str::trim);
+
+                match (pair.next()?, pair.next()) {
+                    ("q", Some(value)) => q = value.parse::<f32>().ok()?.max(0.0).min(1.0),
+                    _ => params += 1,
+                }
+            }
+
+            Some((specificity * 100 + params, q))
+        })
+        .max_by_key(|(specificity, _)| *specificity)
+        .map_or((0.0, 0), |(specificity, q)| (q, specificity / 100))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::TestRequest;
+
+    use super::*;
+
+    const BROWSER: &str = "text/html,application/xhtml+xml,application/xml;q=0.9,image/webp,*/*;q=0.8";
+    // what axios and many fetch wrappers send
+    const XHR: &str = "application/json, text/plain, */*";
+
+    #[test]
+    fn explicit_json_beats_a_wildcard_of_the_same_quality() {
+        let req = TestRequest::get().header(ACCEPT, XHR).to_http_request();
+
+        assert_eq!(quality(Some(XHR), Format::Json), (1.0, 3));
+        assert_eq!(quality(Some(XHR), Format::Html), (1.0, 1));
+        assert_eq!(negotiate(&req, Format::Html), Format::Json);
+    }
+
+    #[test]
+    fn browsers_get_html() {
+        let req = TestRequest::get().header(ACCEPT, BROWSER).to_http_request();
+
+        assert_eq!(quality(Some(BROWSER), Format::Json), (0.8, 1));
+        assert_eq!(negotiate(&req, Format::Html), Format::Html);
+        assert_eq!(negotiate(&req, Format::Json), Format::Html);
+    }
+
+    #[test]
+    fn without_accept_the_default_is_used_unless_the_body_is_json() {
+        let req = TestRequest::get().to_http_request();
+
+        assert_eq!(quality(None, Format::Json), (1.0, 0));
+        assert_eq!(negotiate(&req, Format::Html), Format::Html);
+        assert_eq!(negotiate(&req, Format::Json), Format::Json);
+
+        let req = TestRequest::post().header(CONTENT_TYPE, "application/json").to_http_request();
+
+        assert_eq!(negotiate(&req, Format::Html), Format::Json);
+    }
+
+    #[test]
+    fn wildcards_alone_leave_it_to_the_default() {
+        let req = TestRequest::get().header(ACCEPT, "*/*").to_http_request();
+
+        assert_eq!(negotiate(&req, Format::Html), Format::Html);
+        assert_eq!(negotiate(&req, Format::Json), Format::Json);
+    }
+
+    #[test]
+    fn q_values_outrank_specificity() {
+        let accept = "application/json;q=0.5, */*";
+        let req = TestRequest::get().header(ACCEPT, accept).to_http_request();
+
+        assert_eq!(negotiate(&req, Format::Json), Format::Html);
+    }
+
+    #[test]
+    fn the_most_specific_range_sets_the_quality() {
+        assert_eq!(quality(Some("text/*;q=0.2, text/html;q=0.7"), Format::Html), (0.7, 3));
+        assert_eq!(quality(Some("application/problem+json"), Format::Json), (1.0, 3));
+        assert_eq!(quality(Some("image/png"), Format::Json), (0.0, 0));
+    }
+}
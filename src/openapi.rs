@@ -0,0 +1,215 @@
+use actix_web::HttpResponse;
+use schemars::{
+    gen::{SchemaGenerator, SchemaSettings},
+    JsonSchema
+};
+use serde_json::{json, Map, Value};
+use yarte::Template;
+
+use crate::{
+    account_handler::{AccountExport, DeleteData},
+    auth_handler::AuthData,
+    email_handler::EmailData,
+    errors::{Problem, PROBLEM_CONTENT_TYPE},
+    models::{AccountDeletion, SessionUser},
+    password_handler::{ChangePasswordData, PasswordData},
+    register_handler::RegisterData,
+    reset_handler::ResetPasswordData,
+    templates::ApiDocs,
+    username_handler::UsernameData,
+    vars
+};
+
+pub const API_PREFIX: &str = "/api/v1";
+
+// The cookie set by the session middleware in main.rs
+const SESSION_COOKIE: &str = "actix-session";
+
+
+// One operation of the JSON API. The request and response bodies are described by the
+// types the handlers read and write, so the schemas follow the code.
+struct Endpoint {
+    method: &'static str,
+    path: &'static str,
+    operation_id: &'static str,
+    summary: &'static str,
+    signed_in: bool,
+    parameters: Vec<Value>,
+    request: Option<Value>,
+    responses: Vec<(u16, &'static str, Option<Value>)>,
+}
+
+impl Endpoint {
+    fn new(method: &'static str, path: &'static str, operation_id: &'static str, summary: &'static str) -> Self {
+        // "/register/{path_id}" has a path_id parameter
+        let parameters = path
+            .split('/')
+            .filter_map(|segment| segment.strip_prefix('{')?.strip_suffix('}'))
+            .map(|name| json!({ "name": name, "in": "path", "required": true, "schema": { "type": "string", "format": "uuid" } }))
+            .collect();
+
+        Endpoint { method, path, operation_id, summary, signed_in: false, parameters, request: None, responses: vec![] }
+    }
+
+    fn signed_in(mut self) -> Self {
+        self.signed_in = true;
+        self
+    }
+
+    fn query(mut self, name: &'static str, description: &'static str, values: &[&str]) -> Self {
+        self.parameters.push(json!({
+            "name": name,
+            "in": "query",
+            "required": false,
+            "description": description,
+            "schema": { "type": "string", "enum": values }
+        }));
+        self
+    }
+
+    fn request(mut self, schema: Value) -> Self {
+        self.request = Some(schema);
+        self
+    }
+
+    fn response(mut self, status: u16, description: &'static str, schema: Option<Value>) -> Self {
+        self.responses.push((status, description, schema));
+        self
+    }
+
+    fn into_operation(self, problem: &Value) -> Value {
+        let mut responses = Map::new();
+
+        for (status, description, schema) in self.responses {
+            let mut response = json!({ "description": description });
+
+            if let Some(schema) = schema {
+                response["content"] = json!({ "application/json": { "schema": schema } });
+            }
+
+            responses.insert(status.to_string(), response);
+        }
+
+        responses.insert(String::from("default"), json!({
+            "description": "A problem document, match on its `code`",
+            "content": { PROBLEM_CONTENT_TYPE: { "schema": problem } }
+        }));
+
+        let mut operation = json!({
+            "operationId": self.operation_id,
+            "summary": self.summary,
+            "responses": responses
+        });
+
+        if !self.parameters.is_empty() {
+            operation["parameters"] = Value::Array(self.parameters);
+        }
+
+        if let Some(schema) = self.request {
+            operation["requestBody"] = json!({ "required": true, "content": { "application/json": { "schema": schema } } });
+        }
+
+        if self.signed_in {
+            operation["security"] = json!([{ "session": [] }]);
+        }
+
+        operation
+    }
+}
+
+
+// The OpenAPI 3 document for everything under /api/v1
+pub fn spec() -> Value {
+    let mut gen = SchemaSettings::openapi3().into_generator();
+    let problem = schema::<Problem>(&mut gen);
+    let mut paths = Map::new();
+
+    for endpoint in endpoints(&mut gen) {
+        let (path, method) = (endpoint.path, endpoint.method);
+        let operation = endpoint.into_operation(&problem);
+
+        paths.entry(path).or_insert_with(|| json!({}))[method] = operation;
+    }
+
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "Auth Service",
+            "version": env!("CARGO_PKG_VERSION"),
+            "description": "Accounts, sign-in and sessions. Errors are RFC 7807 problem documents."
+        },
+        "servers": [{ "url": API_PREFIX }],
+        "paths": paths,
+        "components": {
+            "schemas": gen.definitions(),
+            "securitySchemes": {
+                "session": { "type": "apiKey", "in": "cookie", "name": SESSION_COOKIE }
+            }
+        }
+    })
+}
+
+pub async fn openapi_json() -> HttpResponse {
+    HttpResponse::Ok().json(spec())
+}
+
+pub async fn docs() -> HttpResponse {
+    let t = ApiDocs { spec_url: format!("{}/openapi.json", API_PREFIX), script_url: vars::redoc_script_url() };
+
+    HttpResponse::Ok().content_type("text/html; charset=utf-8").body(t.call().unwrap())
+}
+
+
+fn endpoints(gen: &mut SchemaGenerator) -> Vec<Endpoint> {
+    vec![
+        Endpoint::new("post", "/register", "sendConfirmation", "Email a link for creating an account")
+            .request(schema::<RegisterData>(gen))
+            .response(200, "The link was sent, or a notice if the email is already registered", None),
+        Endpoint::new("post", "/register/resend", "resendConfirmation", "Email a fresh link, the same as registering again")
+            .request(schema::<RegisterData>(gen))
+            .response(200, "The link was sent, or a notice if the email is already registered", None),
+        Endpoint::new("post", "/register/{path_id}", "createAccount", "Create the account and sign in")
+            .request(schema::<PasswordData>(gen))
+            .response(201, "The new, signed in user", Some(schema::<SessionUser>(gen))),
+        Endpoint::new("post", "/password/reset", "requestPasswordReset", "Email a link for choosing a new password")
+            .request(schema::<EmailData>(gen))
+            .response(200, "The link was sent, or no account uses the address", None),
+        Endpoint::new("post", "/password/reset/{path_id}", "resetPassword", "Choose a new password with the emailed link")
+            .request(schema::<ResetPasswordData>(gen))
+            .response(204, "The password was changed", None),
+        Endpoint::new("post", "/signin", "signIn", "Sign in with an email address or username and a password")
+            .request(schema::<AuthData>(gen))
+            .response(200, "The signed in user", Some(schema::<SessionUser>(gen))),
+        Endpoint::new("delete", "/signout", "signOut", "End the session")
+            .response(204, "Signed out", None),
+        Endpoint::new("get", "/me", "getCurrentUser", "The signed in user")
+            .signed_in()
+            .response(200, "The signed in user", Some(schema::<SessionUser>(gen))),
+        Endpoint::new("post", "/me/email", "requestEmailChange", "Email a link confirming a new address")
+            .signed_in()
+            .request(schema::<EmailData>(gen))
+            .response(200, "The link was sent to the new address", None),
+        Endpoint::new("post", "/me/password", "changePassword", "Change the password")
+            .signed_in()
+            .request(schema::<ChangePasswordData>(gen))
+            .response(200, "The user, with the new password in effect", Some(schema::<SessionUser>(gen))),
+        Endpoint::new("post", "/me/username", "changeUsername", "Choose or change the username, at most once per cooldown period")
+            .signed_in()
+            .request(schema::<UsernameData>(gen))
+            .response(200, "The user, with the new username", Some(schema::<SessionUser>(gen))),
+        Endpoint::new("get", "/me/export", "exportAccount", "Download everything stored about the user")
+            .signed_in()
+            .query("format", "`zip` for an archive, JSON otherwise", &["json", "zip"])
+            .response(200, "The account's data", Some(schema::<AccountExport>(gen))),
+        Endpoint::new("post", "/me/delete", "deleteAccount", "Delete the account, after a grace period if one is configured")
+            .signed_in()
+            .request(schema::<DeleteData>(gen))
+            .response(202, "The deletion is scheduled and can be cancelled from the email sent", Some(schema::<AccountDeletion>(gen)))
+            .response(204, "The account was deleted", None),
+    ]
+}
+
+// A reference to `T` under components/schemas
+fn schema<T: JsonSchema>(gen: &mut SchemaGenerator) -> Value {
+    serde_json::to_value(gen.subschema_for::<T>()).unwrap()
+}
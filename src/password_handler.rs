@@ -1,47 +1,84 @@
-use actix_web::{error::BlockingError, http::header::LOCATION, web, HttpResponse};
+use actix_web::{http::header::LOCATION, web, HttpRequest, HttpResponse};
 use actix_session::Session;
 use diesel::prelude::*;
+use schemars::JsonSchema;
 use uuid::Uuid;
 use serde::Deserialize;
+use validator::Validate;
 use yarte::Template;
 
 use crate::{
+    credentials::CredentialPool,
+    db,
     models::{Confirmation, Pool, SessionUser, User}, 
-    errors::AuthError, 
+    errors::{AuthError, FieldError}, 
+    metrics,
+    negotiation::{negotiate, Format},
     schema::{
       confirmations::dsl::{id, confirmations},
       users::dsl::users
     },
-    templates::Password,
-    utils::{hash_password, is_signed_in, set_current_user, to_home}
+    templates::{ChangePassword, Notice, Password},
+    hashing::{hash_password, verify},
+    password_history,
+    password_policy,
+    usernames,
+    utils::{
+        clear_password_change_user,
+        get_current_user,
+        get_password_change_user,
+        is_signed_in,
+        set_current_user,
+        to_home,
+        to_return_to_or_home,
+        to_sign_in
+    },
+    validation::{Normalize, Validated}
 };
 
 
-#[derive(Debug, Deserialize)]
+#[derive(Deserialize, JsonSchema, Validate)]
 pub struct PasswordData {
+    #[validate(length(max = 1024, message = "Passwords can be at most 1024 characters long"))]
     pub password: String,
+    // Optional, it can also be chosen later at /me/username
+    #[serde(default)]
+    pub username: Option<String>,
 }
 
-pub async fn create_account(session: Session,
-                            path_id: web::Path<String>,
-                            data: web::Json<PasswordData>,
-                            pool: web::Data<Pool>) -> Result<HttpResponse, AuthError> {
-    if is_signed_in(&session) {
-        return Ok(HttpResponse::BadRequest().finish());
+impl std::fmt::Debug for PasswordData {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PasswordData")
+            .field("password", &"[redacted]")
+            .field("username", &self.username)
+            .finish()
     }
+}
 
-    let result = web::block(move || create_user(&path_id.into_inner(), &data.into_inner().password, &pool)).await;
+#[derive(Deserialize, JsonSchema, Validate)]
+pub struct ChangePasswordData {
+    #[validate(length(min = 1, max = 1024, message = "Enter your current password"))]
+    pub current_password: String,
+    #[validate(length(max = 1024, message = "Passwords can be at most 1024 characters long"))]
+    pub password: String,
+}
 
-    match result {
-        Ok(user) => {
-            set_current_user(&session, &user);
+// Passwords are hashed as typed, see `validation::normalize_text`
+impl Normalize for PasswordData {
+    fn normalize(&mut self) {
+        // an empty form field means no username
+        self.username = self.username.as_deref().map(usernames::normalize).filter(|username| !username.is_empty());
+    }
+}
 
-            Ok(HttpResponse::Created().json(&user))
-        },
-        Err(err) => match err {
-            BlockingError::Error(auth_error) => Err(auth_error),
-            BlockingError::Canceled => Err(AuthError::GenericError(String::from("Could not complete the process"))),
-        },
+impl Normalize for ChangePasswordData {}
+
+impl std::fmt::Debug for ChangePasswordData {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ChangePasswordData")
+            .field("current_password", &"[redacted]")
+            .field("password", &"[redacted]")
+            .finish()
     }
 }
 
@@ -52,10 +89,11 @@ pub async fn show_password_form(session: Session,
         Ok(to_home())
     } else {
         let id_str = path_id.into_inner();
+        let id = id_str.clone();
 
-        match get_invitation(&id_str, &pool) {
+        match db::run(move || get_invitation(&id, &pool)).await {
             Ok(Confirmation { email, .. }) => {
-                let t = Password { path_id: id_str, email, error: None };
+                let t = Password { path_id: id_str, email, error: None, username_errors: vec![], password_errors: vec![] };
 
                 Ok(HttpResponse::Ok().content_type("text/html; charset=utf-8").body(t.call().unwrap()))
             },
@@ -64,25 +102,162 @@ pub async fn show_password_form(session: Session,
     }
 }
 
-pub async fn create_account_for_browser(path_id: web::Path<String>,
-                                        data: web::Form<PasswordData>,
-                                        session: Session,
-                                        pool: web::Data<Pool>) -> Result<HttpResponse, AuthError> {
+pub async fn create_account(session: Session,
+                            path_id: web::Path<String>,
+                            data: Validated<PasswordData>,
+                            req: HttpRequest,
+                            pool: web::Data<Pool>,
+                            credentials: web::Data<CredentialPool>) -> Result<HttpResponse, AuthError> {
+    let format = negotiate(&req, Format::Html);
+
+    if is_signed_in(&session) {
+        return match format {
+            Format::Json => Err(AuthError::GenericError(String::from("You are already signed in"))),
+            Format::Html => Ok(to_home()),
+        };
+    }
+
     let id_str = path_id.into_inner();
     let id_str2 = String::from(id_str.as_str());
-    let result = web::block(move || create_user(&id_str, &data.into_inner().password, &pool)).await;
+    let invitation_pool = pool.clone();
+    let result = match data.into_inner() {
+        Ok(data) => credentials.run(move || create_user(&id_str, &data.password, data.username, &pool)).await,
+        Err(err) => Err(err),
+    };
 
-    match result {
-        Ok(user) => {
+    match (result, format) {
+        (Ok(user), Format::Json) => {
+            set_current_user(&session, &user);
+
+            Ok(HttpResponse::Created().json(&user))
+        },
+        (Err(err), Format::Json) => Err(err),
+        (Ok(user), Format::Html) => {
             set_current_user(&session, &user);
 
-            Ok(to_home())
+            Ok(to_return_to_or_home(&session, None))
+        },
+        (Err(err @ AuthError::ValidationError(_)), Format::Html) => {
+            let id = id_str2.clone();
+            let email = db::run(move || get_invitation(&id, &invitation_pool)).await?.email;
+            let t = Password {
+                path_id: id_str2,
+                email,
+                error: None,
+                username_errors: err.field_messages("username"),
+                password_errors: err.field_messages("password")
+            };
+
+            Ok(HttpResponse::Ok().content_type("text/html; charset=utf-8").body(t.call().unwrap()))
         },
-        Err(_) => {
+        (Err(AuthError::NotFound(_)), Format::Html)
+        | (Err(AuthError::AuthenticationError(_)), Format::Html)
+        | (Err(AuthError::BadId), Format::Html) => {
             let t = Password { 
                 path_id: id_str2, 
                 email: String::from("unknown@email.com"), 
-                error: Some(String::from("Invalid/expired confirmation id"))
+                error: Some(String::from("Invalid/expired confirmation id")),
+                username_errors: vec![],
+                password_errors: vec![]
+            };
+
+            Ok(HttpResponse::Ok().content_type("text/html; charset=utf-8").body(t.call().unwrap()))
+        },
+        // the confirmation may be fine, e.g. when the service is busy, so the form stays usable
+        (Err(err), Format::Html) => {
+            let id = id_str2.clone();
+            let email = db::run(move || get_invitation(&id, &invitation_pool))
+                .await
+                .map(|invitation| invitation.email)
+                .unwrap_or_else(|_| String::from("unknown@email.com"));
+            let t = Password {
+                path_id: id_str2,
+                email,
+                error: Some(err.user_message()),
+                username_errors: vec![],
+                password_errors: vec![]
+            };
+
+            Ok(HttpResponse::Ok().content_type("text/html; charset=utf-8").body(t.call().unwrap()))
+        },
+    }
+}
+
+pub async fn show_change_password_form(session: Session) -> HttpResponse {
+    let expired = match (get_current_user(&session), get_password_change_user(&session)) {
+        (Ok(_), _) => false,
+        (Err(_), Some(_)) => true,
+        (Err(_), None) => return to_sign_in("/me/password"),
+    };
+    let t = ChangePassword { expired, error: None, current_password_errors: vec![], password_errors: vec![] };
+
+    HttpResponse::Ok().content_type("text/html; charset=utf-8").body(t.call().unwrap())
+}
+
+// Signed in users, and those whose password expired at sign-in, can change their password
+pub async fn change_password(session: Session,
+                             data: Validated<ChangePasswordData>,
+                             pool: web::Data<Pool>,
+                             credentials: web::Data<CredentialPool>) -> Result<HttpResponse, AuthError> {
+    let (user, expired) = password_change_user(&session)
+        .ok_or_else(|| AuthError::AuthenticationError(String::from("Unauthorized")))?;
+    let data = data.into_inner()?;
+    let user = credentials.run(move || update_password(user.id, &data.current_password, &data.password, &pool)).await?;
+
+    if expired {
+        clear_password_change_user(&session);
+    }
+
+    set_current_user(&session, &user);
+
+    Ok(HttpResponse::Ok().json(user))
+}
+
+pub async fn change_password_for_browser(session: Session,
+                                         data: Validated<ChangePasswordData>,
+                                         pool: web::Data<Pool>,
+                                         credentials: web::Data<CredentialPool>) -> Result<HttpResponse, AuthError> {
+    let (user, expired) = match password_change_user(&session) {
+        Some(found) => found,
+        None => return Ok(to_sign_in("/me/password")),
+    };
+    let result = match data.into_inner() {
+        Ok(data) => credentials.run(move || update_password(user.id, &data.current_password, &data.password, &pool)).await,
+        Err(err) => Err(err),
+    };
+
+    match result {
+        Ok(user) => {
+            set_current_user(&session, &user);
+
+            if expired {
+                clear_password_change_user(&session);
+
+                return Ok(to_return_to_or_home(&session, None));
+            }
+
+            let t = Notice {
+                title: String::from("Password changed"),
+                message: String::from("Use your new password the next time you sign in"),
+                success: true
+            };
+
+            Ok(HttpResponse::Ok().content_type("text/html; charset=utf-8").body(t.call().unwrap()))
+        },
+        Err(err) => {
+            let t = match err {
+                AuthError::ValidationError(_) => ChangePassword {
+                    expired,
+                    error: None,
+                    current_password_errors: err.field_messages("current_password"),
+                    password_errors: err.field_messages("password"),
+                },
+                _ => ChangePassword {
+                    expired,
+                    error: Some(err.user_message()),
+                    current_password_errors: vec![],
+                    password_errors: vec![],
+                },
             };
 
             Ok(HttpResponse::Ok().content_type("text/html; charset=utf-8").body(t.call().unwrap()))
@@ -94,7 +269,7 @@ pub async fn create_account_for_browser(path_id: web::Path<String>,
 fn get_invitation(path_id: &str, pool: &web::Data<Pool>) -> Result<Confirmation, AuthError> {
     let path_uuid = Uuid::parse_str(path_id)?;
 
-    if let Ok(record) = confirmations.find(path_uuid).get_result::<Confirmation>(&pool.get().unwrap()) {
+    if let Ok(record) = confirmations.find(path_uuid).get_result::<Confirmation>(&db::get(pool)?) {
         Ok(record)
     } else {
         Err(AuthError::AuthenticationError(String::from("Invalid confirmation")))
@@ -102,9 +277,12 @@ fn get_invitation(path_id: &str, pool: &web::Data<Pool>) -> Result<Confirmation,
 }
 
 
-fn create_user(path_id: &str, password: &str, pool: &web::Data<Pool>) -> Result<SessionUser, AuthError> {
+fn create_user(path_id: &str,
+               password: &str,
+               username: Option<String>,
+               pool: &web::Data<Pool>) -> Result<SessionUser, AuthError> {
     let path_uuid = Uuid::parse_str(path_id)?;
-    let conn = &pool.get().unwrap();
+    let conn = &db::get(pool)?;
 
     confirmations
         .filter(id.eq(path_uuid))
@@ -113,15 +291,65 @@ fn create_user(path_id: &str, password: &str, pool: &web::Data<Pool>) -> Result<
         .and_then(|mut result| {
             if let Some(confirmation) = result.pop() {
                 if confirmation.expires_at > chrono::Local::now().naive_local() { // confirmation has not expired
-                    let password: String = hash_password(password)?;
-                    let user: User = diesel::insert_into(users)
-                                            .values(&User::from(confirmation.email, password))
-                                            .get_result(conn)?;
+                    password_policy::check(password, &confirmation.email)?;
+
+                    if let Some(username) = &username {
+                        usernames::check(username)?;
+                    }
+
+                    let password = hash_password(password)?;
+
+                    return conn.transaction(|| {
+                        let mut new_user = User::from(confirmation.email, password);
+
+                        if let Some(username) = username {
+                            usernames::ensure_available(&username, None, conn)?;
+                            new_user.username = Some(username);
+                        }
+
+                        let user: User = diesel::insert_into(users)
+                                                .values(&new_user)
+                                                .get_result(conn)?;
+
+                        // the confirmation is spent once the account exists
+                        diesel::delete(confirmations.find(confirmation.id)).execute(conn)?;
+
+                        metrics::REGISTRATIONS.inc();
 
-                    return Ok(user.into());
+                        Ok(user.into())
+                    });
                 }
             }
 
             Err(AuthError::AuthenticationError(String::from("Invalid confirmation")))
         })
-}
\ No newline at end of file
+}
+// The user and whether they're here because their password expired
+fn password_change_user(session: &Session) -> Option<(SessionUser, bool)> {
+    match get_current_user(session) {
+        Ok(user) => Some((user, false)),
+        Err(_) => get_password_change_user(session).map(|user| (user, true)),
+    }
+}
+
+fn update_password(user_id: Uuid,
+                   current_password: &str,
+                   password: &str,
+                   pool: &web::Data<Pool>) -> Result<SessionUser, AuthError> {
+    let conn = &db::get(pool)?;
+    let user = users.find(user_id).get_result::<User>(conn)?;
+
+    if !verify(&user.hash, &user.key_id, current_password).unwrap_or(false) {
+        return Err(AuthError::ValidationError(vec![FieldError {
+            field: String::from("current_password"),
+            message: String::from("Current password is incorrect"),
+        }]));
+    }
+
+    password_policy::check(password, &user.email)?;
+    password_history::check_reuse(&user, password, conn)?;
+
+    let updated = password_history::change(&user, hash_password(password)?, conn)?;
+
+    Ok(updated.into())
+}
@@ -0,0 +1,92 @@
+use diesel::prelude::*;
+use tracing::warn;
+use uuid::Uuid;
+
+use crate::{
+    errors::{AuthError, FieldError},
+    hashing::{verify, PasswordHash},
+    models::{PasswordHistory, User},
+    schema::{password_history, users},
+    vars
+};
+
+
+// Whether the user's password is older than `PASSWORD_MAX_AGE_DAYS`
+pub fn is_expired(user: &User) -> bool {
+    let max_age_days = vars::password_max_age_days();
+
+    max_age_days > 0
+        && user.password_changed_at + chrono::Duration::days(max_age_days) < chrono::Local::now().naive_local()
+}
+
+// Rejects the current password and the ones before it, `PASSWORD_HISTORY_SIZE` in all
+pub fn check_reuse(user: &User, password: &str, conn: &PgConnection) -> Result<(), AuthError> {
+    let size = vars::password_history_size();
+
+    if size <= 0 {
+        return Ok(());
+    }
+
+    let previous = password_history::table
+        .filter(password_history::user_id.eq(user.id))
+        .order(password_history::created_at.desc())
+        .limit(size - 1)
+        .load::<PasswordHistory>(conn)?;
+    // an entry made with a retired key can't be checked, it's logged rather than held against
+    // the user, who couldn't get past it
+    let reused = std::iter::once((user.hash.as_str(), user.key_id.as_str()))
+        .chain(previous.iter().map(|entry| (entry.hash.as_str(), entry.key_id.as_str())))
+        .any(|(hash, key_id)| match verify(hash, key_id, password) {
+            Ok(matched) => matched,
+            Err(err) => {
+                warn!(user_id = %user.id, key_id, "Could not check a previous password for reuse: {}", err);
+
+                false
+            },
+        });
+
+    if reused {
+        Err(AuthError::ValidationError(vec![FieldError {
+            field: String::from("password"),
+            message: format!("Password must differ from your last {} password(s)", size),
+        }]))
+    } else {
+        Ok(())
+    }
+}
+
+// Replaces the user's password, keeping the old hash in the history
+pub fn change(user: &User, new_hash: PasswordHash, conn: &PgConnection) -> Result<User, AuthError> {
+    conn.transaction(|| {
+        diesel::insert_into(password_history::table).values(&PasswordHistory::from(user)).execute(conn)?;
+
+        let updated: User = diesel::update(users::table.find(user.id))
+            .set((
+                users::hash.eq(new_hash.hash),
+                users::key_id.eq(new_hash.key_id),
+                users::password_changed_at.eq(chrono::Local::now().naive_local())
+            ))
+            .get_result(conn)?;
+
+        prune(user.id, conn)?;
+
+        Ok(updated)
+    })
+}
+
+
+fn prune(user_id: Uuid, conn: &PgConnection) -> Result<(), AuthError> {
+    let kept = password_history::table
+        .select(password_history::id)
+        .filter(password_history::user_id.eq(user_id))
+        .order(password_history::created_at.desc())
+        .limit(vars::password_history_size().max(0))
+        .load::<Uuid>(conn)?;
+
+    diesel::delete(
+        password_history::table.filter(password_history::user_id.eq(user_id).and(password_history::id.ne_all(kept)))
+    )
+    .execute(conn)?;
+
+    Ok(())
+}
@@ -0,0 +1,147 @@
+use std::{
+    fs::File,
+    io::{BufRead, BufReader},
+    path::Path
+};
+
+use sha1::{Digest, Sha1};
+use tracing::warn;
+
+use crate::{
+    errors::{AuthError, FieldError},
+    vars
+};
+
+
+// Checks a new password against the `PASSWORD_*` policy, reporting every rule it breaks
+pub fn check(password: &str, email: &str) -> Result<(), AuthError> {
+    let mut errors = vec![];
+    let length = password.chars().count();
+    let min_length = vars::password_min_length();
+    let max_length = vars::password_max_length();
+
+    if length < min_length {
+        errors.push(format!("Password must be at least {} characters long", min_length));
+    }
+
+    if length > max_length {
+        errors.push(format!("Password must be at most {} characters long", max_length));
+    }
+
+    if vars::password_forbid_email() && contains_email(password, email) {
+        errors.push(String::from("Password must not contain your email address"));
+    }
+
+    // only score passwords that pass the cheaper rules, zxcvbn is slow on long input
+    if errors.is_empty() && vars::password_min_score() > 0 {
+        let score = zxcvbn::zxcvbn(password, &[email]).map_or(0, |entropy| entropy.score());
+
+        if score < vars::password_min_score() {
+            errors.push(String::from("Password is too easy to guess, try a longer phrase or fewer common words"));
+        }
+    }
+
+    if let Some(dir) = vars::password_breach_corpus_dir() {
+        if is_breached(password, Path::new(&dir)) {
+            errors.push(String::from("Password has appeared in a data breach, choose another one"));
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(AuthError::ValidationError(
+            errors.into_iter().map(|message| FieldError { field: String::from("password"), message }).collect()
+        ))
+    }
+}
+
+
+fn contains_email(password: &str, email: &str) -> bool {
+    let password = password.to_lowercase();
+    let email = email.to_lowercase();
+    let local_part = email.split('@').next().unwrap_or_default();
+
+    password.contains(&email) || (local_part.chars().count() >= 3 && password.contains(local_part))
+}
+
+// The corpus is laid out like the Pwned Passwords range API: a file per 5 character
+// SHA-1 prefix, named after the prefix, holding "SUFFIX:COUNT" lines. Only the prefix
+// file is read, so the whole hash is never looked up.
+fn is_breached(password: &str, dir: &Path) -> bool {
+    let hash = hex::encode_upper(Sha1::digest(password.as_bytes()));
+    let (prefix, suffix) = hash.split_at(5);
+    let file = match File::open(dir.join(prefix)).or_else(|_| File::open(dir.join(format!("{}.txt", prefix)))) {
+        Ok(file) => file,
+        Err(err) => {
+            warn!("Could not read breach corpus range {}: {}", prefix, err);
+            return false;
+        },
+    };
+
+    BufReader::new(file)
+        .lines()
+        .filter_map(Result::ok)
+        .any(|line| line.split(':').next().map_or(false, |candidate| candidate.eq_ignore_ascii_case(suffix)))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // run with the default policy
+    fn messages(password: &str, email: &str) -> Vec<String> {
+        check(password, email).err().map_or(vec![], |err| err.field_messages("password"))
+    }
+
+    #[test]
+    fn strong_passwords_pass() {
+        assert!(check("ug4#Tq!vp9Lw-mZ2", "alice@example.com").is_ok());
+    }
+
+    #[test]
+    fn length_is_checked_before_anything_else() {
+        assert_eq!(messages("abc", "alice@example.com"), vec![String::from("Password must be at least 8 characters long")]);
+        assert_eq!(
+            messages(&"a".repeat(129), "alice@example.com"),
+            vec![String::from("Password must be at most 128 characters long")]
+        );
+    }
+
+    #[test]
+    fn passwords_may_not_contain_the_email() {
+        let expected = String::from("Password must not contain your email address");
+
+        assert!(messages("ug4#ALICE@example.com", "alice@example.com").contains(&expected));
+        assert!(messages("ug4#Tq!Alice-mZ2", "alice@example.com").contains(&expected));
+        // local parts this short would rule out too much
+        assert!(!messages("ug4#Tq!al-vp9Lw-mZ2", "al@example.com").contains(&expected));
+    }
+
+    #[test]
+    fn guessable_passwords_are_refused() {
+        assert_eq!(
+            messages("password1", "alice@example.com"),
+            vec![String::from("Password is too easy to guess, try a longer phrase or fewer common words")]
+        );
+    }
+
+    #[test]
+    fn breached_passwords_are_found_by_their_range_file() {
+        let dir = std::env::temp_dir().join(format!("breach-corpus-{}", std::process::id()));
+
+        std::fs::create_dir_all(&dir).unwrap();
+        // SHA-1("hunter2") is F3BBBD66A63D4BF1747940578EC3D0103530E21D
+        std::fs::write(
+            dir.join("F3BBB.txt"),
+            "0000000000000000000000000000000000A:1\r\nd66a63d4bf1747940578ec3d0103530e21d:23\r\n"
+        )
+        .unwrap();
+
+        assert!(is_breached("hunter2", &dir));
+        assert!(!is_breached("hunter3", &dir));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,82 +1,164 @@
-use actix_web::{error::BlockingError, web, HttpResponse};
+use actix_web::{web, HttpRequest, HttpResponse};
 use actix_session::Session;
 use diesel::prelude::*;
+use schemars::JsonSchema;
 use serde::Deserialize;
+use validator::Validate;
 use yarte::Template;
 
 use crate::{
-    email_service::send_confirmation_mail, 
+    auth_handler::ReturnTo,
+    db,
+    email_service::{send_confirmation_mail, send_registered_notice, SharedMailer},
+    emails,
     errors::AuthError, 
     models::{Confirmation, Pool},
+    negotiation::{negotiate, Format},
+    schema::{confirmations, users},
     templates::Register,
-    utils::{is_signed_in, to_home}
+    utils::{is_signed_in, remember_return_to, to_home},
+    validation::{normalize_text, Normalize, Validated},
+    vars
 };
 
 
-#[derive(Deserialize)]
+#[derive(Deserialize, JsonSchema, Validate)]
 pub struct RegisterData {
+    #[validate(
+        email(message = "Enter a valid email address"),
+        length(max = 50, message = "Email addresses can be at most 50 characters long")
+    )]
     pub email: String,
 }
 
+impl Normalize for RegisterData {
+    fn normalize(&mut self) {
+        self.email = normalize_text(&self.email);
+    }
+}
+
 pub async fn send_confirmation(session: Session,
-                              data: web::Json<RegisterData>,
-                              pool: web::Data<Pool>) -> Result<HttpResponse, AuthError> {
+                              data: Validated<RegisterData>,
+                              req: HttpRequest,
+                              pool: web::Data<Pool>,
+                              mailer: web::Data<SharedMailer>) -> Result<HttpResponse, AuthError> {
+    let format = negotiate(&req, Format::Html);
+
     if is_signed_in(&session) {
-        return Ok(HttpResponse::BadRequest().finish());
-    }
-            
-    let result = web::block(move || create_confirmation(data.into_inner().email, &pool)).await;
-
-    match result {
-        Ok(_) => Ok(HttpResponse::Ok().finish()),
-        Err(err) => match err {
-            BlockingError::Error(auth_error) => Err(auth_error),
-            BlockingError::Canceled => Err(AuthError::GenericError(String::from("Could not complete the process"))),
-        },
+        return already_signed_in(format);
     }
+
+    let email = match data.into_parts() {
+        (data, Ok(_)) => data.email,
+        (data, Err(err)) => return respond_to_register(Err(err), data.email, format),
+    };
+    let email2 = email.clone();
+    let result = db::run(move || create_confirmation(email, &pool, &mailer)).await;
+
+    respond_to_register(result, email2, format)
 }
 
-pub async fn show_confirmation_form(session: Session) -> Result<HttpResponse, AuthError> {
+pub async fn show_confirmation_form(session: Session, query: web::Query<ReturnTo>) -> Result<HttpResponse, AuthError> {
     if is_signed_in(&session) {
         Ok(to_home())
     } else {
-        let template = Register { sent: false, error: None };
+        // so the user ends up where they started once registration is complete
+        if let Some(return_to) = &query.return_to {
+            remember_return_to(&session, return_to);
+        }
+
+        let template = Register { sent: false, email: String::new(), error: None, email_errors: vec![] };
 
         Ok(HttpResponse::Ok().content_type("text/html; charset=utf-8").body(template.call().unwrap()))
     }
 }
 
-pub async fn send_confirmation_for_browser(data: web::Form<RegisterData>,
-                                          pool: web::Data<Pool>) -> Result<HttpResponse, AuthError> {
-    let result = web::block(move || create_confirmation(data.into_inner().email, &pool)).await;
+// The same as registering again, so it can't be used to find out which addresses have a
+// pending registration or an account either
+pub async fn resend_confirmation(session: Session,
+                                 data: Validated<RegisterData>,
+                                 req: HttpRequest,
+                                 pool: web::Data<Pool>,
+                                 mailer: web::Data<SharedMailer>) -> Result<HttpResponse, AuthError> {
+    send_confirmation(session, data, req, pool, mailer).await
+}
+
+
+fn already_signed_in(format: Format) -> Result<HttpResponse, AuthError> {
+    match format {
+        Format::Json => Err(AuthError::GenericError(String::from("You are already signed in"))),
+        Format::Html => Ok(to_home()),
+    }
+}
+
+fn respond_to_register(result: Result<(), AuthError>, email: String, format: Format) -> Result<HttpResponse, AuthError> {
+    if format == Format::Json {
+        return result.map(|_| HttpResponse::Ok().finish());
+    }
+
     let template = match result {
-        Ok(_) => Register { sent: true, error: None },
-        Err(err) => match err {
-            BlockingError::Error(auth_error) => Register { sent: false, error: Some(auth_error.to_string()) },
-            BlockingError::Canceled => {
-                Register { sent: false, error: Some(String::from("Could not complete the process")) }
-            }
+        Ok(_) => Register { sent: true, email, error: None, email_errors: vec![] },
+        Err(err @ AuthError::ValidationError(_)) => {
+            Register { sent: false, email, error: None, email_errors: err.field_messages("email") }
         },
+        Err(err) => Register { sent: false, email, error: Some(err.user_message()), email_errors: vec![] },
     };
 
     Ok(HttpResponse::Ok().content_type("text/html; charset=utf-8").body(template.call().unwrap()))
 }
 
+// Registered and new addresses go through the same steps, a confirmation record with its
+// cooldown and one email, so neither the response nor its timing tells them apart. The
+// record for a registered address is never sent out and can't create a second account.
+fn create_confirmation(email: String, pool: &web::Data<Pool>, mailer: &web::Data<SharedMailer>) -> Result<(), AuthError> {
+    let conn = &db::get(pool)?;
+    let registered = user_exists(&email, conn)?;
+    let confirmation = upsert_record(email, conn)?;
 
-fn create_confirmation(email: String, pool: &web::Data<Pool>) -> Result<(), AuthError> {
-    let confirmation = insert_record(email, pool)?;
+    if registered {
+        send_registered_notice(mailer.get_ref().as_ref(), &confirmation.email)
+    } else {
+        send_confirmation_mail(mailer.get_ref().as_ref(), &confirmation)
+    }
+}
 
-    send_confirmation_mail(&confirmation)
+fn user_exists(email: &str, conn: &PgConnection) -> Result<bool, AuthError> {
+    let count = users::table
+        .filter(users::normalized_email.eq(emails::normalize(email)))
+        .count()
+        .get_result::<i64>(conn)?;
+
+    Ok(count > 0)
 }
 
-fn insert_record(email: String, pool: &web::Data<Pool>) -> Result<Confirmation, AuthError> {
-    use crate::schema::confirmations::dsl::confirmations;
+// Inserts a new confirmation or re-issues the pending one with a fresh id and expiry
+fn upsert_record(email: String, conn: &PgConnection) -> Result<Confirmation, AuthError> {
+    let existing = confirmations::table
+        .filter(confirmations::normalized_email.eq(emails::normalize(&email)))
+        .first::<Confirmation>(conn)
+        .optional()?;
+
+    if let Some(Confirmation { sent_at, .. }) = existing {
+        let cooldown_ends = sent_at + chrono::Duration::seconds(vars::confirmation_resend_cooldown_secs());
+
+        if cooldown_ends > chrono::Local::now().naive_local() {
+            return Err(AuthError::TooManyRequests(String::from("Please wait a moment before requesting another link")));
+        }
+    }
 
     let new_record : Confirmation = email.into();
 
-    let inserted_record = diesel::insert_into(confirmations)
+    let upserted_record = diesel::insert_into(confirmations::table)
                                 .values(&new_record)
-                                .get_result(&pool.get().unwrap())?;
-
-    Ok(inserted_record)
-}
\ No newline at end of file
+                                .on_conflict(confirmations::normalized_email)
+                                .do_update()
+                                .set((
+                                    confirmations::id.eq(new_record.id),
+                                    confirmations::email.eq(&new_record.email),
+                                    confirmations::expires_at.eq(new_record.expires_at),
+                                    confirmations::sent_at.eq(new_record.sent_at)
+                                ))
+                                .get_result(conn)?;
+
+    Ok(upserted_record)
+}
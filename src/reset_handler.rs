@@ -0,0 +1,205 @@
+use actix_web::{http::header::LOCATION, web, HttpRequest, HttpResponse};
+use diesel::prelude::*;
+use schemars::JsonSchema;
+use serde::Deserialize;
+use tracing::warn;
+use uuid::Uuid;
+use validator::Validate;
+use yarte::Template;
+
+use crate::{
+    credentials::CredentialPool,
+    db,
+    email_handler::EmailData,
+    email_service::{send_password_reset_mail, SharedMailer},
+    emails,
+    errors::AuthError,
+    hashing::hash_password,
+    models::{PasswordReset, Pool, User},
+    negotiation::{negotiate, Format},
+    password_history,
+    password_policy,
+    schema::{password_resets, users},
+    templates::{Notice, ResetPassword, ResetRequest},
+    validation::{Normalize, Validated},
+    vars
+};
+
+
+#[derive(Deserialize, JsonSchema, Validate)]
+pub struct ResetPasswordData {
+    #[validate(length(max = 1024, message = "Passwords can be at most 1024 characters long"))]
+    pub password: String,
+}
+
+// Passwords are hashed as typed, see `validation::normalize_text`
+impl Normalize for ResetPasswordData {}
+
+impl std::fmt::Debug for ResetPasswordData {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ResetPasswordData").field("password", &"[redacted]").finish()
+    }
+}
+
+pub async fn show_reset_request_form() -> HttpResponse {
+    let t = ResetRequest { sent: false, email: String::new(), error: None, email_errors: vec![] };
+
+    HttpResponse::Ok().content_type("text/html; charset=utf-8").body(t.call().unwrap())
+}
+
+// Answers the same whether or not the address belongs to an account, so it can't be used
+// to find out which do
+pub async fn request_reset(data: Validated<EmailData>,
+                           req: HttpRequest,
+                           pool: web::Data<Pool>,
+                           mailer: web::Data<SharedMailer>) -> Result<HttpResponse, AuthError> {
+    let format = negotiate(&req, Format::Html);
+    let email = match data.into_parts() {
+        (data, Ok(_)) => data.email,
+        (data, Err(err)) => return respond_to_request(Err(err), data.email, format),
+    };
+    let email2 = email.clone();
+    let result = db::run(move || create_reset(&email, &pool, &mailer)).await;
+
+    respond_to_request(result, email2, format)
+}
+
+pub async fn show_reset_form(path_id: web::Path<String>, pool: web::Data<Pool>) -> Result<HttpResponse, AuthError> {
+    let id = path_id.into_inner();
+    let path_id = id.clone();
+
+    match db::run(move || find_reset(&id, &pool)).await {
+        Ok(_) => {
+            let t = ResetPassword { path_id, error: None, password_errors: vec![] };
+
+            Ok(HttpResponse::Ok().content_type("text/html; charset=utf-8").body(t.call().unwrap()))
+        },
+        Err(_) => Ok(HttpResponse::Found().header(LOCATION, "/password/reset").finish()),
+    }
+}
+
+// The new password has to pass the same policy and history checks as a change
+pub async fn reset_password(path_id: web::Path<String>,
+                            data: Validated<ResetPasswordData>,
+                            req: HttpRequest,
+                            pool: web::Data<Pool>,
+                            credentials: web::Data<CredentialPool>) -> Result<HttpResponse, AuthError> {
+    let format = negotiate(&req, Format::Html);
+    let path_id = path_id.into_inner();
+    let id = path_id.clone();
+    let result = match data.into_inner() {
+        Ok(data) => credentials.run(move || complete_reset(&id, &data.password, &pool)).await,
+        Err(err) => Err(err),
+    };
+
+    let template = match (result, format) {
+        (Ok(_), Format::Json) => return Ok(HttpResponse::NoContent().finish()),
+        (Err(err), Format::Json) => return Err(err),
+        (Ok(_), Format::Html) => {
+            let t = Notice {
+                title: String::from("Password reset"),
+                message: String::from("Sign in with your new password"),
+                success: true
+            };
+
+            return Ok(HttpResponse::Ok().content_type("text/html; charset=utf-8").body(t.call().unwrap()));
+        },
+        (Err(err @ AuthError::ValidationError(_)), Format::Html) => {
+            ResetPassword { path_id, error: None, password_errors: err.field_messages("password") }
+        },
+        (Err(err), Format::Html) => ResetPassword { path_id, error: Some(err.user_message()), password_errors: vec![] },
+    };
+
+    Ok(HttpResponse::Ok().content_type("text/html; charset=utf-8").body(template.call().unwrap()))
+}
+
+
+fn respond_to_request(result: Result<(), AuthError>, email: String, format: Format) -> Result<HttpResponse, AuthError> {
+    if format == Format::Json {
+        return result.map(|_| HttpResponse::Ok().finish());
+    }
+
+    let template = match result {
+        Ok(_) => ResetRequest { sent: true, email, error: None, email_errors: vec![] },
+        Err(err @ AuthError::ValidationError(_)) => {
+            ResetRequest { sent: false, email, error: None, email_errors: err.field_messages("email") }
+        },
+        Err(err) => ResetRequest { sent: false, email, error: Some(err.user_message()), email_errors: vec![] },
+    };
+
+    Ok(HttpResponse::Ok().content_type("text/html; charset=utf-8").body(template.call().unwrap()))
+}
+
+fn create_reset(email: &str, pool: &web::Data<Pool>, mailer: &web::Data<SharedMailer>) -> Result<(), AuthError> {
+    let conn = &db::get(pool)?;
+    let user = match users::table
+        .filter(users::normalized_email.eq(emails::normalize(email)))
+        .filter(users::disabled_at.is_null())
+        .first::<User>(conn)
+        .optional()?
+    {
+        Some(user) => user,
+        None => return Ok(()),
+    };
+    let cooldown_started = chrono::Local::now().naive_local()
+        - chrono::Duration::seconds(vars::confirmation_resend_cooldown_secs());
+    let recent = password_resets::table
+        .filter(password_resets::user_id.eq(user.id))
+        .filter(password_resets::created_at.gt(cooldown_started))
+        .count()
+        .get_result::<i64>(conn)?;
+
+    if recent > 0 {
+        return Ok(());
+    }
+
+    // only the most recent link works
+    diesel::delete(password_resets::table.filter(password_resets::user_id.eq(user.id))).execute(conn)?;
+
+    let reset: PasswordReset = diesel::insert_into(password_resets::table)
+                                    .values(&PasswordReset::from(&user))
+                                    .get_result(conn)?;
+
+    // failing here would tell the caller the account exists
+    if let Err(err) = send_password_reset_mail(mailer.get_ref().as_ref(), &user.email, &reset) {
+        warn!(user_id = %user.id, "Could not send password reset email: {}", err);
+    }
+
+    Ok(())
+}
+
+fn find_reset(path_id: &str, pool: &web::Data<Pool>) -> Result<PasswordReset, AuthError> {
+    let path_uuid = Uuid::parse_str(path_id)?;
+
+    password_resets::table
+        .filter(password_resets::id.eq(path_uuid))
+        .filter(password_resets::expires_at.gt(chrono::Local::now().naive_local()))
+        .first::<PasswordReset>(&db::get(pool)?)
+        .optional()?
+        .ok_or_else(|| AuthError::NotFound(String::from("This link has expired, ask for another one")))
+}
+
+fn complete_reset(path_id: &str, password: &str, pool: &web::Data<Pool>) -> Result<(), AuthError> {
+    let reset = find_reset(path_id, pool)?;
+    let conn = &db::get(pool)?;
+    let user = users::table.find(reset.user_id).get_result::<User>(conn)?;
+
+    // the account may have been disabled since the link was sent
+    if user.disabled_at.is_some() {
+        return Err(AuthError::NotFound(String::from("This link has expired, ask for another one")));
+    }
+
+    password_policy::check(password, &user.email)?;
+    password_history::check_reuse(&user, password, conn)?;
+
+    let hash = hash_password(password)?;
+
+    conn.transaction(|| {
+        password_history::change(&user, hash, conn)?;
+
+        // the link is spent, along with any other the user asked for
+        diesel::delete(password_resets::table.filter(password_resets::user_id.eq(user.id))).execute(conn)?;
+
+        Ok(())
+    })
+}
@@ -0,0 +1,199 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+        Mutex
+    },
+    time::Duration
+};
+
+use diesel::prelude::*;
+use serde::Serialize;
+use tracing::{info, warn};
+
+use crate::{
+    account_handler::remove_due_accounts,
+    db,
+    errors::AuthError,
+    metrics,
+    models::Pool,
+    schema::{confirmations, email_changes, password_resets},
+    vars
+};
+
+
+#[derive(Clone, Debug, Serialize)]
+pub struct MaintenanceReport {
+    pub started_at: chrono::NaiveDateTime,
+    pub finished_at: chrono::NaiveDateTime,
+    pub expired_confirmations: usize,
+    pub expired_email_changes: usize,
+    pub expired_password_resets: usize,
+    pub removed_accounts: usize,
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct SchedulerStatus {
+    pub interval_secs: u64,
+    pub runs: u64,
+    pub failures: u64,
+    pub expired_confirmations_total: u64,
+    pub expired_email_changes_total: u64,
+    pub expired_password_resets_total: u64,
+    pub removed_accounts_total: u64,
+    pub last_report: Option<MaintenanceReport>,
+    pub last_error: Option<String>,
+}
+
+#[derive(Clone)]
+pub struct Scheduler {
+    pool: Pool,
+    running: Arc<AtomicBool>,
+    status: Arc<Mutex<SchedulerStatus>>,
+}
+
+impl Scheduler {
+    pub fn new(pool: Pool) -> Self {
+        let status = SchedulerStatus { interval_secs: vars::maintenance_interval_secs(), ..Default::default() };
+
+        Scheduler {
+            pool,
+            running: Arc::new(AtomicBool::new(false)),
+            status: Arc::new(Mutex::new(status)),
+        }
+    }
+
+    // Runs maintenance every `MAINTENANCE_INTERVAL_SECS` on the current actix system
+    pub fn start(&self) {
+        let scheduler = self.clone();
+        let interval_secs = self.status().interval_secs;
+
+        if interval_secs == 0 {
+            info!("Maintenance scheduler is disabled");
+            return;
+        }
+
+        actix_rt::spawn(async move {
+            let mut interval = actix_rt::time::interval(Duration::from_secs(interval_secs));
+
+            loop {
+                interval.tick().await;
+
+                // failures are recorded in the status, so there's nothing more to do here
+                let _ = scheduler.run_now().await;
+            }
+        });
+    }
+
+    pub async fn run_now(&self) -> Result<MaintenanceReport, AuthError> {
+        if self.running.compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst).is_err() {
+            return Err(AuthError::TooManyRequests(String::from("A maintenance run is already in progress")));
+        }
+
+        let running = Running(self.running.clone());
+        let pool = self.pool.clone();
+        // the flag goes with the work rather than this future, which is dropped when the
+        // admin client disconnects while the run carries on
+        let result = db::run(move || {
+            let _running = running;
+
+            run(&pool)
+        })
+        .await;
+
+        self.record(&result);
+
+        result
+    }
+
+    pub fn status(&self) -> SchedulerStatus {
+        self.status.lock().unwrap().clone()
+    }
+
+    fn record(&self, result: &Result<MaintenanceReport, AuthError>) {
+        let mut status = self.status.lock().unwrap();
+
+        status.runs += 1;
+
+        match result {
+            Ok(report) => {
+                info!(
+                    "Maintenance run removed {} expired confirmation(s), {} expired email change(s), {} expired password reset(s) \
+                     and {} account(s)",
+                    report.expired_confirmations,
+                    report.expired_email_changes,
+                    report.expired_password_resets,
+                    report.removed_accounts
+                );
+
+                metrics::MAINTENANCE_RUNS.with_label_values(&["success"]).inc();
+                metrics::MAINTENANCE_REMOVED.with_label_values(&["confirmations"]).inc_by(report.expired_confirmations as i64);
+                metrics::MAINTENANCE_REMOVED.with_label_values(&["email_changes"]).inc_by(report.expired_email_changes as i64);
+                metrics::MAINTENANCE_REMOVED.with_label_values(&["password_resets"]).inc_by(report.expired_password_resets as i64);
+                metrics::MAINTENANCE_REMOVED.with_label_values(&["accounts"]).inc_by(report.removed_accounts as i64);
+
+                status.expired_confirmations_total += report.expired_confirmations as u64;
+                status.expired_email_changes_total += report.expired_email_changes as u64;
+                status.expired_password_resets_total += report.expired_password_resets as u64;
+                status.removed_accounts_total += report.removed_accounts as u64;
+                status.last_report = Some(report.clone());
+                status.last_error = None;
+            },
+            Err(err) => {
+                warn!("Maintenance run failed: {}", err);
+
+                metrics::MAINTENANCE_RUNS.with_label_values(&["failure"]).inc();
+
+                status.failures += 1;
+                status.last_error = Some(err.to_string());
+            },
+        }
+    }
+}
+
+// Clears `Scheduler::running` once a run is over however it ends: finished, panicked or
+// dropped before it started
+struct Running(Arc<AtomicBool>);
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::SeqCst);
+    }
+}
+
+
+fn run(pool: &Pool) -> Result<MaintenanceReport, AuthError> {
+    let conn = &db::get(pool)?;
+    let started_at = chrono::Local::now().naive_local();
+    let revert_deadline = started_at - chrono::Duration::days(vars::email_change_revert_days());
+
+    let expired_confirmations = diesel::delete(
+        confirmations::table.filter(confirmations::expires_at.lt(started_at))
+    )
+    .execute(conn)?;
+
+    // pending changes that can no longer be confirmed and applied ones that can no longer be reverted
+    let expired_email_changes = diesel::delete(
+        email_changes::table.filter(
+            email_changes::confirmed_at.is_null().and(email_changes::expires_at.lt(started_at))
+                .or(email_changes::confirmed_at.lt(revert_deadline))
+        )
+    )
+    .execute(conn)?;
+
+    let expired_password_resets = diesel::delete(
+        password_resets::table.filter(password_resets::expires_at.lt(started_at))
+    )
+    .execute(conn)?;
+
+    let removed_accounts = remove_due_accounts(conn)?;
+
+    Ok(MaintenanceReport {
+        started_at,
+        finished_at: chrono::Local::now().naive_local(),
+        expired_confirmations,
+        expired_email_changes,
+        expired_password_resets,
+        removed_accounts,
+    })
+}
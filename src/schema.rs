@@ -1,8 +1,50 @@
+table! {
+    account_deletions (id) {
+        id -> Uuid,
+        user_id -> Uuid,
+        scheduled_for -> Timestamp,
+        created_at -> Timestamp,
+    }
+}
+
 table! {
     confirmations (id) {
         id -> Uuid,
         email -> Varchar,
         expires_at -> Timestamp,
+        sent_at -> Timestamp,
+        normalized_email -> Varchar,
+    }
+}
+
+table! {
+    email_changes (id) {
+        id -> Uuid,
+        revert_id -> Uuid,
+        user_id -> Uuid,
+        old_email -> Varchar,
+        new_email -> Varchar,
+        expires_at -> Timestamp,
+        confirmed_at -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    password_history (id) {
+        id -> Uuid,
+        user_id -> Uuid,
+        hash -> Varchar,
+        key_id -> Varchar,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    password_resets (id) {
+        id -> Uuid,
+        user_id -> Uuid,
+        expires_at -> Timestamp,
+        created_at -> Timestamp,
     }
 }
 
@@ -12,10 +54,26 @@ table! {
         email -> Varchar,
         hash -> Varchar,
         created_at -> Timestamp,
+        roles -> Array<Text>,
+        disabled_at -> Nullable<Timestamp>,
+        key_id -> Varchar,
+        password_changed_at -> Timestamp,
+        normalized_email -> Varchar,
+        username -> Nullable<Varchar>,
+        username_changed_at -> Nullable<Timestamp>,
     }
 }
 
+joinable!(account_deletions -> users (user_id));
+joinable!(email_changes -> users (user_id));
+joinable!(password_history -> users (user_id));
+joinable!(password_resets -> users (user_id));
+
 allow_tables_to_appear_in_same_query!(
+    account_deletions,
     confirmations,
+    email_changes,
+    password_history,
+    password_resets,
     users,
 );
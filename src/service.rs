@@ -0,0 +1,279 @@
+use std::sync::Arc;
+
+use actix_service::Service;
+use actix_web::{web, HttpMessage};
+
+use crate::{
+    account_handler,
+    admin_handler,
+    auth_handler,
+    credentials::CredentialPool,
+    email_handler,
+    email_service::{Mailer, SharedMailer, SmtpMailer},
+    hashing,
+    health::{self, Health},
+    metrics,
+    models::Pool,
+    negotiation::Format,
+    openapi::{self, API_PREFIX},
+    password_handler,
+    register_handler,
+    reset_handler,
+    scheduler::Scheduler,
+    sessions::SessionCheck,
+    username_handler,
+    vars
+};
+
+
+// Every path `configure` mounts, plus the /metrics route main.rs can add, so request
+// metrics can be labelled by route rather than by path. Literal paths come before the
+// patterns they'd also match, e.g. "/register/resend" before "/register/{path_id}".
+pub const ROUTES: [&str; 46] = [
+    "/metrics",
+    "/healthz",
+    "/readyz",
+    "/admin/migrations",
+    "/admin/hashes",
+    "/admin/emails",
+    "/admin/maintenance",
+    "/api/v1/openapi.json",
+    "/api/v1/docs",
+    "/api/v1/register",
+    "/api/v1/register/resend",
+    "/api/v1/register/{path_id}",
+    "/api/v1/password/reset",
+    "/api/v1/password/reset/{path_id}",
+    "/api/v1/signin",
+    "/api/v1/signout",
+    "/api/v1/me",
+    "/api/v1/me/email",
+    "/api/v1/me/password",
+    "/api/v1/me/username",
+    "/api/v1/me/export",
+    "/api/v1/me/delete",
+    "/register",
+    "/register/resend",
+    "/register/{path_id}",
+    "/password/reset",
+    "/password/reset/{path_id}",
+    "/me",
+    "/me/email",
+    "/me/password",
+    "/me/password2",
+    "/me/username",
+    "/me/export",
+    "/me/delete",
+    "/me/delete2",
+    "/me/delete/{path_id}/cancel",
+    "/me/email2",
+    "/me/email/{path_id}",
+    "/me/email/{path_id}/revert",
+    "/signout",
+    "/signin",
+    "/verify",
+    "/signin2",
+    "/register2",
+    "/register2/resend",
+    "/register2/{path_id}",
+];
+
+// Mounts the auth routes onto an actix `App`:
+//
+//     let auth = AuthService::builder().store(pool).mailer(SmtpMailer).build();
+//
+//     HttpServer::new(move || {
+//         App::new()
+//             .wrap(RedisSession::new("127.0.0.1:6379", &key))
+//             .configure(|cfg| auth.configure(cfg))
+//     })
+//
+// The handlers read the signed in user from `actix_session::Session`, so the
+// `App` needs a session middleware.
+#[derive(Clone)]
+pub struct AuthService {
+    pool: Pool,
+    mailer: SharedMailer,
+    scheduler: Scheduler,
+    health: Health,
+    credentials: CredentialPool,
+}
+
+#[derive(Default)]
+pub struct AuthServiceBuilder {
+    pool: Option<Pool>,
+    mailer: Option<SharedMailer>,
+    credentials: Option<CredentialPool>,
+}
+
+impl AuthService {
+    pub fn builder() -> AuthServiceBuilder {
+        AuthServiceBuilder::default()
+    }
+
+    pub fn pool(&self) -> &Pool {
+        &self.pool
+    }
+
+    pub fn scheduler(&self) -> &Scheduler {
+        &self.scheduler
+    }
+
+    pub fn health(&self) -> &Health {
+        &self.health
+    }
+
+    pub fn configure(&self, cfg: &mut web::ServiceConfig) {
+        // without a token, metrics are only served on the separate METRICS_PORT
+        if vars::metrics_token().is_some() && vars::metrics_port().is_none() {
+            cfg.route("/metrics", web::get().to(metrics::metrics));
+        }
+
+        cfg
+            .data(self.pool.clone())
+            .data(self.mailer.clone())
+            .data(self.scheduler.clone())
+            .data(self.health.clone())
+            .data(self.credentials.clone())
+            .route("/healthz", web::get().to(health::liveness))
+            .route("/readyz", web::get().to(health::readiness))
+            .route("/admin/migrations", web::get().to(admin_handler::migration_status))
+            .route("/admin/hashes", web::get().to(admin_handler::hash_report))
+            .route("/admin/emails", web::get().to(admin_handler::email_report))
+            .service(
+                web::resource("/admin/maintenance")
+                    .route(web::get().to(admin_handler::maintenance_status))
+                    .route(web::post().to(admin_handler::run_maintenance)),
+            )
+            // The JSON API, described at /api/v1/openapi.json
+            .service(
+                web::scope(API_PREFIX)
+                    // answer with JSON unless a client asks for HTML
+                    .wrap_fn(|req, srv| {
+                        req.extensions_mut().insert(Format::Json);
+                        srv.call(req)
+                    })
+                    .wrap(SessionCheck::new(self.pool.clone()))
+                    .route("/openapi.json", web::get().to(openapi::openapi_json))
+                    .route("/docs", web::get().to(openapi::docs))
+                    .route("/register", web::post().to(register_handler::send_confirmation))
+                    .route("/register/resend", web::post().to(register_handler::resend_confirmation))
+                    .route("/register/{path_id}", web::post().to(password_handler::create_account))
+                    .route("/password/reset", web::post().to(reset_handler::request_reset))
+                    .route("/password/reset/{path_id}", web::post().to(reset_handler::reset_password))
+                    .route("/signin", web::post().to(auth_handler::sign_in))
+                    .route("/signout", web::delete().to(auth_handler::sign_out))
+                    .route("/me", web::get().to(auth_handler::me))
+                    .route("/me/email", web::post().to(email_handler::request_email_change))
+                    .route("/me/password", web::post().to(password_handler::change_password))
+                    .route("/me/username", web::post().to(username_handler::change_username))
+                    .route("/me/export", web::get().to(account_handler::export))
+                    .route("/me/delete", web::post().to(account_handler::delete_account)),
+            )
+            // Routes
+            .service(
+                web::scope("/")
+                    .wrap(SessionCheck::new(self.pool.clone()))
+                    .service(
+                        web::resource("/register")
+                            .route(web::get().to(register_handler::show_confirmation_form))
+                            .route(web::post().to(register_handler::send_confirmation)),
+                    )
+                    .route("/register/resend", web::post().to(register_handler::resend_confirmation))
+                    .service(
+                        web::resource("/register/{path_id}")
+                            .route(web::get().to(password_handler::show_password_form))
+                            .route(web::post().to(password_handler::create_account)),
+                    )
+                    .service(
+                        web::resource("/password/reset")
+                            .route(web::get().to(reset_handler::show_reset_request_form))
+                            .route(web::post().to(reset_handler::request_reset)),
+                    )
+                    .service(
+                        web::resource("/password/reset/{path_id}")
+                            .route(web::get().to(reset_handler::show_reset_form))
+                            .route(web::post().to(reset_handler::reset_password)),
+                    )
+                    .route("/me", web::get().to(auth_handler::me))
+                    .service(
+                        web::resource("/me/email")
+                            .route(web::get().to(email_handler::show_email_form))
+                            .route(web::post().to(email_handler::request_email_change)),
+                    )
+                    .service(
+                        web::resource("/me/password")
+                            .route(web::get().to(password_handler::show_change_password_form))
+                            .route(web::post().to(password_handler::change_password)),
+                    )
+                    .route("/me/password2", web::post().to(password_handler::change_password_for_browser))
+                    .service(
+                        web::resource("/me/username")
+                            .route(web::get().to(username_handler::show_username_form))
+                            .route(web::post().to(username_handler::change_username)),
+                    )
+                    .route("/me/export", web::get().to(account_handler::export))
+                    .service(
+                        web::resource("/me/delete")
+                            .route(web::get().to(account_handler::show_delete_form))
+                            .route(web::post().to(account_handler::delete_account)),
+                    )
+                    .route("/me/delete2", web::post().to(account_handler::delete_account_for_browser))
+                    .route("/me/delete/{path_id}/cancel", web::get().to(account_handler::cancel_deletion))
+                    .route("/me/email2", web::post().to(email_handler::request_email_change_for_browser))
+                    .route("/me/email/{path_id}", web::get().to(email_handler::confirm_email_change))
+                    .route("/me/email/{path_id}/revert", web::get().to(email_handler::revert_email_change))
+                    .service(
+                        web::resource("/signout")
+                            .route(web::get().to(auth_handler::sign_out))
+                            .route(web::delete().to(auth_handler::sign_out)),
+                    )
+                    .service(
+                        web::resource("/signin")
+                            .route(web::get().to(auth_handler::show_sign_in_form))
+                            .route(web::post().to(auth_handler::sign_in)),
+                    )
+                    .route("/verify", web::get().to(auth_handler::verify_request))
+                    // the form-only routes that came before content negotiation, for pages still open in browsers
+                    .route("/signin2", web::post().to(auth_handler::sign_in))
+                    .route("/register2", web::post().to(register_handler::send_confirmation))
+                    .route("/register2/resend", web::post().to(register_handler::resend_confirmation))
+                    .route("/register2/{path_id}", web::post().to(password_handler::create_account)),
+            );
+    }
+}
+
+impl AuthServiceBuilder {
+    pub fn store(mut self, pool: Pool) -> Self {
+        self.pool = Some(pool);
+        self
+    }
+
+    // Defaults to `SmtpMailer`
+    pub fn mailer<M: Mailer + 'static>(mut self, mailer: M) -> Self {
+        self.mailer = Some(Arc::new(mailer));
+        self
+    }
+
+    // Defaults to `CredentialPool::from_env()`
+    pub fn credential_pool(mut self, credentials: CredentialPool) -> Self {
+        self.credentials = Some(credentials);
+        self
+    }
+
+    pub fn build(self) -> AuthService {
+        let pool = self.pool.expect("AuthService needs a store");
+
+        if let Err(err) = hashing::check_keys() {
+            panic!("{}", err);
+        }
+
+        AuthService {
+            scheduler: Scheduler::new(pool.clone()),
+            health: Health::new(),
+            credentials: self.credentials.unwrap_or_else(CredentialPool::from_env),
+            mailer: self.mailer.unwrap_or_else(|| Arc::new(SmtpMailer)),
+            pool,
+        }
+    }
+}
@@ -0,0 +1,117 @@
+use std::{
+    cell::RefCell,
+    future::Future,
+    pin::Pin,
+    rc::Rc,
+    task::{Context, Poll}
+};
+
+use actix_service::{Service, Transform};
+use actix_session::{Session, UserSession};
+use actix_web::{
+    dev::{ServiceRequest, ServiceResponse},
+    Error
+};
+use diesel::prelude::*;
+use futures::future::{ok, Ready};
+
+use crate::{
+    db,
+    errors::AuthError,
+    models::{Pool, SessionUser, User},
+    schema::users,
+    utils::{get_current_user, get_password_change_user, set_current_user}
+};
+
+
+// Middleware checking the session's user against the database before every request, so
+// disabling an account or revoking a role takes effect at once rather than when the user
+// next signs in. Handlers can trust `get_current_user` behind it.
+pub struct SessionCheck {
+    pool: Pool,
+}
+
+impl SessionCheck {
+    pub fn new(pool: Pool) -> Self {
+        SessionCheck { pool }
+    }
+}
+
+impl<S, B> Transform<S> for SessionCheck
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = SessionCheckMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(SessionCheckMiddleware { service: Rc::new(RefCell::new(service)), pool: self.pool.clone() })
+    }
+}
+
+pub struct SessionCheckMiddleware<S> {
+    // shared with the future, which calls it once the check is done
+    service: Rc<RefCell<S>>,
+    pool: Pool,
+}
+
+impl<S, B> Service for SessionCheckMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.borrow_mut().poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let pool = self.pool.clone();
+
+        Box::pin(async move {
+            refresh(&req.get_session(), pool).await?;
+
+            let fut = service.borrow_mut().call(req);
+
+            fut.await
+        })
+    }
+}
+
+
+// Replaces the session's user with the database's copy, or clears the session when the
+// account is gone or disabled. Users who signed in with an expired password are checked too.
+async fn refresh(session: &Session, pool: Pool) -> Result<(), AuthError> {
+    let (user_id, current) = match (get_current_user(session), get_password_change_user(session)) {
+        (Ok(user), _) => (user.id, Some(user)),
+        (Err(_), Some(user)) => (user.id, None),
+        (Err(_), None) => return Ok(()),
+    };
+    let record = db::run(move || Ok(users::table.find(user_id).get_result::<User>(&db::get(&pool)?).optional()?)).await?;
+
+    match (record, current) {
+        (Some(user), current) if user.disabled_at.is_none() => {
+            let user = SessionUser::from(user);
+
+            // only written when it changed, so the session store isn't written on every request
+            if current.map_or(false, |current| current != user) {
+                set_current_user(session, &user);
+            }
+        },
+        _ => session.clear(),
+    }
+
+    Ok(())
+}
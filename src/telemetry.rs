@@ -0,0 +1,190 @@
+use std::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Instant
+};
+
+use actix_service::{Service, Transform};
+use actix_web::{
+    dev::{ServiceRequest, ServiceResponse},
+    http::{HeaderName, HeaderValue, StatusCode},
+    Error,
+    HttpMessage,
+    HttpRequest
+};
+use futures::future::{ok, Ready};
+use tracing::{error, info, info_span};
+use tracing_futures::Instrument;
+use tracing_subscriber::{fmt, layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
+use uuid::Uuid;
+
+use crate::{errors::AuthError, vars};
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+
+// The id of the request being handled, either taken from `X-Request-Id` or generated
+#[derive(Clone, Debug)]
+pub struct RequestId(pub String);
+
+// Sets up logging from `RUST_LOG`, as JSON when `LOG_FORMAT=json`, and exports spans
+// to the OTLP collector at `OTEL_EXPORTER_OTLP_ENDPOINT` when built with `--features otlp`
+pub fn init() {
+    let filter = EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| EnvFilter::new("actix_web=info,actix_server=info,auth_service=info"));
+    let json = vars::log_format() == "json";
+
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(if json { Some(fmt::layer().json()) } else { None })
+        .with(if json { None } else { Some(fmt::layer()) })
+        .with(otlp_layer())
+        .init();
+}
+
+pub fn request_id(req: &HttpRequest) -> Option<String> {
+    req.extensions().get::<RequestId>().map(|RequestId(id)| id.clone())
+}
+
+// "alice@example.com" becomes "a***@example.com"
+pub fn redact_email(email: &str) -> String {
+    match email.find('@') {
+        Some(at) => format!("{}***{}", email.chars().next().unwrap_or('*'), &email[at..]),
+        None => String::from("***"),
+    }
+}
+
+// Middleware giving every request a span tagged with its request id, which is also
+// added to the problem documents of failed requests
+pub struct RequestTracing;
+
+impl<S, B> Transform<S> for RequestTracing
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = RequestTracingMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(RequestTracingMiddleware { service })
+    }
+}
+
+pub struct RequestTracingMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service for RequestTracingMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let started = Instant::now();
+        let request_id = req
+            .headers()
+            .get(REQUEST_ID_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .filter(|id| is_valid_request_id(id))
+            .map(String::from)
+            .unwrap_or_else(|| Uuid::new_v4().to_string());
+        let span = info_span!(
+            "request",
+            request_id = %request_id,
+            method = %req.method(),
+            path = %req.path()
+        );
+
+        req.extensions_mut().insert(RequestId(request_id.clone()));
+
+        let fut = span.in_scope(|| self.service.call(req));
+
+        Box::pin(
+            async move {
+                let mut res = fut.await?;
+                let status = res.status();
+                let problem = res
+                    .response()
+                    .error()
+                    .and_then(|err| match err.as_error::<AuthError>() {
+                        Some(err) => Some(err.clone()),
+                        // malformed bodies and queries rejected by the extractors
+                        None if status == StatusCode::BAD_REQUEST => Some(AuthError::GenericError(err.to_string())),
+                        None => None,
+                    })
+                    .map(|err| {
+                        if let AuthError::ProcessError(message) = &err {
+                            error!("{}", message);
+                        }
+
+                        err.problem_response(Some(request_id.clone()))
+                    });
+
+                if let Some(problem) = problem {
+                    res = res.into_response(problem.into_body());
+                }
+
+                info!(status = res.status().as_u16(), elapsed_ms = started.elapsed().as_millis() as u64, "finished");
+
+                if let Ok(value) = HeaderValue::from_str(&request_id) {
+                    res.headers_mut().insert(HeaderName::from_static(REQUEST_ID_HEADER), value);
+                }
+
+                Ok(res)
+            }
+            .instrument(span)
+        )
+    }
+}
+
+
+// Propagated ids end up in logs and headers, so keep them short and plain
+fn is_valid_request_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= 64
+        && id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+}
+
+#[cfg(feature = "otlp")]
+fn otlp_layer<S>() -> Option<tracing_opentelemetry::OpenTelemetryLayer<S, opentelemetry::sdk::trace::Tracer>>
+where
+    S: tracing::Subscriber + for<'span> tracing_subscriber::registry::LookupSpan<'span>,
+{
+    use opentelemetry::{sdk::{trace, Resource}, KeyValue};
+
+    let endpoint = vars::otlp_endpoint()?;
+    let (tracer, uninstall) = opentelemetry_otlp::new_pipeline()
+        .with_endpoint(endpoint)
+        .with_trace_config(
+            trace::config().with_resource(Resource::new(vec![KeyValue::new("service.name", "auth_service")]))
+        )
+        .install()
+        .expect("Failed to set up the OTLP exporter");
+
+    // keep exporting until the process exits
+    std::mem::forget(uninstall);
+
+    Some(tracing_opentelemetry::layer().with_tracer(tracer))
+}
+
+#[cfg(not(feature = "otlp"))]
+fn otlp_layer() -> Option<tracing_subscriber::layer::Identity> {
+    None
+}
@@ -6,7 +6,9 @@ use crate::models::SessionUser;
 #[template(path = "pages/register.hbs")]
 pub struct Register {
     pub sent: bool,
-    pub error: Option<String>
+    pub email: String,
+    pub error: Option<String>,
+    pub email_errors: Vec<String>,
 }
 
 #[derive(Template)]
@@ -14,7 +16,35 @@ pub struct Register {
 pub struct Password {
     pub email: String,
     pub path_id: String,
-    pub error: Option<String>
+    pub error: Option<String>,
+    pub username_errors: Vec<String>,
+    pub password_errors: Vec<String>,
+}
+
+#[derive(Template)]
+#[template(path = "pages/reset_request.hbs")]
+pub struct ResetRequest {
+    pub sent: bool,
+    pub email: String,
+    pub error: Option<String>,
+    pub email_errors: Vec<String>,
+}
+
+#[derive(Template)]
+#[template(path = "pages/reset_password.hbs")]
+pub struct ResetPassword {
+    pub path_id: String,
+    pub error: Option<String>,
+    pub password_errors: Vec<String>,
+}
+
+#[derive(Template)]
+#[template(path = "pages/change_password.hbs")]
+pub struct ChangePassword {
+    pub expired: bool,
+    pub error: Option<String>,
+    pub current_password_errors: Vec<String>,
+    pub password_errors: Vec<String>,
 }
 
 #[derive(Template)]
@@ -27,4 +57,48 @@ pub struct Me {
 #[template(path = "pages/sign_in.hbs")]
 pub struct SignIn {
     pub error: Option<String>,
+    pub login: String,
+    pub return_to: Option<String>,
+    pub login_errors: Vec<String>,
+    pub password_errors: Vec<String>,
+}
+
+#[derive(Template)]
+#[template(path = "pages/email.hbs")]
+pub struct ChangeEmail {
+    pub email: String,
+    pub sent: bool,
+    pub error: Option<String>,
+    pub email_errors: Vec<String>,
+}
+
+#[derive(Template)]
+#[template(path = "pages/username.hbs")]
+pub struct ChangeUsername {
+    pub username: Option<String>,
+    pub changed: bool,
+    pub error: Option<String>,
+    pub username_errors: Vec<String>,
+}
+
+#[derive(Template)]
+#[template(path = "pages/notice.hbs")]
+pub struct Notice {
+    pub title: String,
+    pub message: String,
+    pub success: bool,
+}
+
+#[derive(Template)]
+#[template(path = "pages/delete.hbs")]
+pub struct DeleteAccount {
+    pub email: String,
+    pub error: Option<String>,
+}
+
+#[derive(Template)]
+#[template(path = "pages/api_docs.hbs")]
+pub struct ApiDocs {
+    pub spec_url: String,
+    pub script_url: String,
 }
\ No newline at end of file
@@ -0,0 +1,94 @@
+use actix_web::{web, HttpRequest, HttpResponse};
+use actix_session::Session;
+use diesel::prelude::*;
+use schemars::JsonSchema;
+use serde::Deserialize;
+use uuid::Uuid;
+use validator::Validate;
+use yarte::Template;
+
+use crate::{
+    db,
+    errors::AuthError,
+    models::{Pool, SessionUser, User},
+    negotiation::{negotiate, Format},
+    schema::users,
+    templates::ChangeUsername,
+    usernames,
+    utils::{get_current_user, set_current_user, to_sign_in},
+    validation::{Normalize, Validated}
+};
+
+
+#[derive(Debug, Deserialize, JsonSchema, Validate)]
+pub struct UsernameData {
+    #[validate(length(min = 1, message = "Enter a username"))]
+    pub username: String,
+}
+
+impl Normalize for UsernameData {
+    fn normalize(&mut self) {
+        self.username = usernames::normalize(&self.username);
+    }
+}
+
+pub async fn show_username_form(session: Session) -> HttpResponse {
+    match get_current_user(&session) {
+        Ok(user) => {
+            let t = ChangeUsername { username: user.username, changed: false, error: None, username_errors: vec![] };
+
+            HttpResponse::Ok().content_type("text/html; charset=utf-8").body(t.call().unwrap())
+        },
+        Err(_) => to_sign_in("/me/username"),
+    }
+}
+
+// Serves both API clients and the form at /me/username, see `negotiation::negotiate`
+pub async fn change_username(session: Session,
+                             data: Validated<UsernameData>,
+                             req: HttpRequest,
+                             pool: web::Data<Pool>) -> Result<HttpResponse, AuthError> {
+    let format = negotiate(&req, Format::Html);
+    let user = match (get_current_user(&session), format) {
+        (Ok(user), _) => user,
+        (Err(err), Format::Json) => return Err(err),
+        (Err(_), Format::Html) => return Ok(to_sign_in("/me/username")),
+    };
+    let current_username = user.username.clone();
+    let result = match data.into_inner() {
+        Ok(data) => db::run(move || update_username(user.id, &data.username, &pool)).await,
+        Err(err) => Err(err),
+    };
+
+    if let Ok(user) = &result {
+        set_current_user(&session, user);
+    }
+
+    let template = match (result, format) {
+        (Ok(user), Format::Json) => return Ok(HttpResponse::Ok().json(user)),
+        (Err(err), Format::Json) => return Err(err),
+        (Ok(user), Format::Html) => ChangeUsername { username: user.username, changed: true, error: None, username_errors: vec![] },
+        (Err(err @ AuthError::ValidationError(_)), Format::Html) => ChangeUsername {
+            username: current_username,
+            changed: false,
+            error: None,
+            username_errors: err.field_messages("username"),
+        },
+        (Err(err), Format::Html) => ChangeUsername {
+            username: current_username,
+            changed: false,
+            error: Some(err.user_message()),
+            username_errors: vec![],
+        },
+    };
+
+    Ok(HttpResponse::Ok().content_type("text/html; charset=utf-8").body(template.call().unwrap()))
+}
+
+
+fn update_username(user_id: Uuid, username: &str, pool: &web::Data<Pool>) -> Result<SessionUser, AuthError> {
+    let conn = &db::get(pool)?;
+    let user = users::table.find(user_id).get_result::<User>(conn)?;
+
+    Ok(usernames::change(&user, username, conn)?.into())
+}
@@ -0,0 +1,169 @@
+use diesel::{pg::PgConnection, prelude::*};
+use uuid::Uuid;
+
+use crate::{
+    errors::{AuthError, FieldError},
+    models::User,
+    schema::users,
+    validation::normalize_text,
+    vars
+};
+
+const MIN_LENGTH: usize = 3;
+// the width of users.username
+const MAX_LENGTH: usize = 32;
+
+// Names that could pass for the service, its staff or its routes
+const RESERVED: [&str; 18] = [
+    "admin",
+    "administrator",
+    "api",
+    "auth",
+    "help",
+    "me",
+    "moderator",
+    "null",
+    "register",
+    "root",
+    "security",
+    "signin",
+    "signout",
+    "staff",
+    "support",
+    "system",
+    "undefined",
+    "www",
+];
+
+
+// Usernames are kept lowercase, so "Alice" and "alice" are one name
+pub fn normalize(username: &str) -> String {
+    normalize_text(username).to_lowercase()
+}
+
+// Checks a normalized username against the naming rules, reporting every rule it breaks
+pub fn check(username: &str) -> Result<(), AuthError> {
+    let mut errors = vec![];
+    let length = username.chars().count();
+
+    if length < MIN_LENGTH || length > MAX_LENGTH {
+        errors.push(format!("Usernames must be between {} and {} characters long", MIN_LENGTH, MAX_LENGTH));
+    }
+
+    if !username.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '.' || c == '-' || c == '_') {
+        errors.push(String::from("Usernames can only contain letters, digits, '.', '-' and '_'"));
+    }
+
+    let is_alphanumeric = |c: Option<char>| c.map_or(false, |c| c.is_ascii_alphanumeric());
+
+    if !is_alphanumeric(username.chars().next()) || !is_alphanumeric(username.chars().last()) {
+        errors.push(String::from("Usernames must start and end with a letter or digit"));
+    }
+
+    if RESERVED.contains(&username) || vars::username_reserved().iter().any(|name| name == username) {
+        errors.push(String::from("That username is reserved"));
+    }
+
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(AuthError::ValidationError(
+            errors.into_iter().map(|message| FieldError { field: String::from("username"), message }).collect()
+        ))
+    }
+}
+
+// Taken by anyone but `owner`
+pub fn ensure_available(username: &str, owner: Option<Uuid>, conn: &PgConnection) -> Result<(), AuthError> {
+    let taken = users::table
+        .filter(users::username.eq(username))
+        .filter(users::id.ne(owner.unwrap_or_else(Uuid::nil)))
+        .count()
+        .get_result::<i64>(conn)?;
+
+    if taken > 0 {
+        Err(AuthError::ValidationError(vec![FieldError {
+            field: String::from("username"),
+            message: String::from("That username is taken"),
+        }]))
+    } else {
+        Ok(())
+    }
+}
+
+// Picks or replaces the user's username, at most once per USERNAME_CHANGE_COOLDOWN_DAYS
+pub fn change(user: &User, username: &str, conn: &PgConnection) -> Result<User, AuthError> {
+    check(username)?;
+
+    if user.username.as_deref() == Some(username) {
+        return Err(AuthError::GenericError(String::from("This is already your username")));
+    }
+
+    if let Some(changed_at) = user.username_changed_at {
+        let cooldown_ends = changed_at + chrono::Duration::days(vars::username_change_cooldown_days());
+
+        if cooldown_ends > chrono::Local::now().naive_local() {
+            return Err(AuthError::TooManyRequests(format!(
+                "You can change your username again on {}",
+                cooldown_ends.format("%Y-%m-%d")
+            )));
+        }
+    }
+
+    ensure_available(username, Some(user.id), conn)?;
+
+    let updated = diesel::update(users::table.find(user.id))
+        .set((
+            users::username.eq(Some(username)),
+            users::username_changed_at.eq(Some(chrono::Local::now().naive_local()))
+        ))
+        .get_result::<User>(conn)?;
+
+    Ok(updated)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn messages(username: &str) -> Vec<String> {
+        check(username).err().map_or(vec![], |err| err.field_messages("username"))
+    }
+
+    #[test]
+    fn usernames_are_trimmed_and_lowercased() {
+        assert_eq!(normalize("  Alice "), "alice");
+        // "é" typed as "e" and a combining accent
+        assert_eq!(normalize("Re\u{301}mi"), "r\u{e9}mi");
+    }
+
+    #[test]
+    fn good_usernames_pass() {
+        assert!(check("alice").is_ok());
+        assert!(check("a.l-i_ce99").is_ok());
+        assert!(check(&"a".repeat(MAX_LENGTH)).is_ok());
+    }
+
+    #[test]
+    fn every_broken_rule_is_reported() {
+        assert_eq!(messages("_a"), vec![
+            String::from("Usernames must be between 3 and 32 characters long"),
+            String::from("Usernames must start and end with a letter or digit"),
+        ]);
+        assert_eq!(messages(&"a".repeat(MAX_LENGTH + 1)).len(), 1);
+    }
+
+    #[test]
+    fn only_lowercase_ascii_is_allowed() {
+        assert_eq!(messages("Alice"), vec![String::from("Usernames can only contain letters, digits, '.', '-' and '_'")]);
+        assert_eq!(messages("r\u{e9}mi").len(), 1);
+        assert_eq!(messages("ali ce").len(), 1);
+    }
+
+    #[test]
+    fn reserved_names_are_refused() {
+        assert_eq!(messages("admin"), vec![String::from("That username is reserved")]);
+        assert_eq!(messages("signin"), vec![String::from("That username is reserved")]);
+    }
+}
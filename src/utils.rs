@@ -1,40 +1,16 @@
-use argonautica::{Hasher, Verifier};
+use url::{form_urlencoded::byte_serialize, Position, Url};
 use actix_session::Session;
 use actix_web::{
-  http::header::{CONTENT_TYPE, LOCATION}, 
+  http::header::{AUTHORIZATION, LOCATION}, 
   HttpRequest, 
   HttpResponse
 };
 
-use crate::{errors::AuthError, vars, models::SessionUser};
+use crate::{errors::AuthError, metrics, vars, models::SessionUser};
 
+const RETURN_TO_KEY: &str = "return_to";
+const PASSWORD_CHANGE_KEY: &str = "password_change_user";
 
-pub fn hash_password(password: &str) -> Result<String, AuthError> {
-  Hasher::default()
-      .with_password(password)
-      .with_secret_key(vars::secret_key().as_str())
-      .hash()
-      .map_err(|_| AuthError::AuthenticationError(String::from("Could not hash password")))
-}
-
-pub fn verify(hash: &str, password: &str) -> Result<bool, AuthError> {
-  Verifier::default()
-      .with_hash(hash)
-      .with_password(password)
-      .with_secret_key(vars::secret_key().as_str())
-      .verify()
-      .map_err(|_| AuthError::AuthenticationError(String::from("Could not verify password")))
-}
-
-pub fn is_json_request(req: &HttpRequest) -> bool {
-    req
-      .headers()
-      .get(CONTENT_TYPE)
-      .map_or(
-        false,
-        |header| header.to_str().map_or(false, |content_type| "application/json" == content_type)
-      )
-}
 
 pub fn is_signed_in(session: &Session) -> bool {
   match get_current_user(session) {
@@ -46,21 +22,221 @@ pub fn is_signed_in(session: &Session) -> bool {
 pub fn set_current_user(session: &Session, user: &SessionUser) -> () {
     // serializing to string is alright for this case, 
     // but binary would be preferred in production use-cases.
-    session.set("user", serde_json::to_string(user).unwrap()).unwrap();
+    session
+        .set("user", serde_json::to_string(user).unwrap())
+        .map_err(|err| {
+            metrics::SESSION_ERRORS.inc();
+            err
+        })
+        .unwrap();
 }
 
 pub fn get_current_user(session: &Session) -> Result<SessionUser, AuthError> {
     let msg = "Could not retrieve user from session";
 
     session.get::<String>("user")
-        .map_err(|_| AuthError::AuthenticationError(String::from(msg)))
-        .unwrap()
+        .map_err(|_| {
+            metrics::SESSION_ERRORS.inc();
+            AuthError::AuthenticationError(String::from(msg))
+        })?
         .map_or(
           Err(AuthError::AuthenticationError(String::from(msg))),
           |user| serde_json::from_str(&user).or_else(|_| Err(AuthError::AuthenticationError(String::from(msg)))) 
         ) 
 }
 
+// A user who signed in with an expired password, and may only change it until they do
+pub fn set_password_change_user(session: &Session, user: &SessionUser) {
+  session.set(PASSWORD_CHANGE_KEY, serde_json::to_string(user).unwrap()).unwrap();
+}
+
+pub fn get_password_change_user(session: &Session) -> Option<SessionUser> {
+  session
+    .get::<String>(PASSWORD_CHANGE_KEY)
+    .ok()
+    .flatten()
+    .and_then(|user| serde_json::from_str(&user).ok())
+}
+
+pub fn clear_password_change_user(session: &Session) {
+  session.remove(PASSWORD_CHANGE_KEY);
+}
+
 pub fn to_home() -> HttpResponse {
   HttpResponse::Found().header(LOCATION, "/me").finish()
-}
\ No newline at end of file
+}
+
+pub fn redirect_to(location: &str) -> HttpResponse {
+  HttpResponse::Found().header(LOCATION, location).finish()
+}
+
+// Relative paths, or absolute URLs on this host or a host in `RETURN_TO_HOSTS`,
+// are safe to redirect to as long as the path is allowed by `RETURN_TO_PATHS`
+pub fn safe_return_to(return_to: &str) -> Option<String> {
+  if return_to.is_empty() || return_to.chars().any(|c| c.is_control() || c == '\\') {
+    return None;
+  }
+
+  if return_to.starts_with('/') {
+    // "//evil.com" is a protocol-relative URL to another host
+    if return_to.starts_with("//") {
+      return None;
+    }
+
+    // checked as the browser will resolve it, "/app/../admin" and "/app/%2e%2e/admin" are "/admin"
+    let url = Url::parse(&vars::domain_url()).ok()?.join(return_to).ok()?;
+
+    return if is_allowed_path(url.path()) {
+      Some(String::from(&url[Position::BeforePath..]))
+    } else {
+      None
+    };
+  }
+
+  let url = Url::parse(return_to).ok()?;
+  let own_host = Url::parse(&vars::domain_url()).ok().and_then(|own| own.host_str().map(String::from));
+  let allowed_hosts = vars::return_to_hosts();
+
+  match (url.scheme(), url.host_str()) {
+    ("http", Some(host)) | ("https", Some(host)) => {
+      let host_is_allowed = own_host.as_deref() == Some(host) || allowed_hosts.iter().any(|allowed| allowed == host);
+
+      if host_is_allowed && is_allowed_path(url.path()) {
+        Some(url.into_string())
+      } else {
+        None
+      }
+    },
+    _ => None,
+  }
+}
+
+// Keeps a return_to around until the user has signed in, e.g. across registration
+pub fn remember_return_to(session: &Session, return_to: &str) {
+  if let Some(url) = safe_return_to(return_to) {
+    session.set(RETURN_TO_KEY, url).unwrap();
+  }
+}
+
+// Redirects to the given return_to, else the one remembered in the session, else home
+pub fn to_return_to_or_home(session: &Session, return_to: Option<&str>) -> HttpResponse {
+  let remembered = session.get::<String>(RETURN_TO_KEY).ok().flatten();
+
+  session.remove(RETURN_TO_KEY);
+
+  return_to
+    .and_then(safe_return_to)
+    .or_else(|| remembered.and_then(|url| safe_return_to(&url)))
+    .map_or_else(to_home, |url| redirect_to(&url))
+}
+
+// Sends an anonymous user to sign in, coming back to `return_to` afterwards
+pub fn to_sign_in(return_to: &str) -> HttpResponse {
+  redirect_to(&format!("/signin?return_to={}", byte_serialize(return_to.as_bytes()).collect::<String>()))
+}
+
+
+// Checks for `Authorization: Bearer <token>`
+pub fn authorize_bearer(req: &HttpRequest, token: &str) -> Result<(), AuthError> {
+  let provided = req
+      .headers()
+      .get(AUTHORIZATION)
+      .and_then(|header| header.to_str().ok())
+      .and_then(|header| header.strip_prefix("Bearer "))
+      .ok_or_else(|| AuthError::AuthenticationError(String::from("Unauthorized")))?;
+
+  if constant_time_eq(provided.as_bytes(), token.as_bytes()) {
+    Ok(())
+  } else {
+    Err(AuthError::AuthenticationError(String::from("Unauthorized")))
+  }
+}
+
+// Prefixes match whole segments, so "/app" allows "/app" and "/app/settings" but not "/application"
+fn is_allowed_path(path: &str) -> bool {
+  let allowed_paths = vars::return_to_paths();
+
+  allowed_paths.is_empty() || allowed_paths.iter().any(|prefix| {
+    let prefix = prefix.trim_end_matches('/');
+
+    path == prefix || path.starts_with(&format!("{}/", prefix))
+  })
+}
+
+
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+  a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // every test sets the same values, so they can run side by side
+  fn configure() {
+    std::env::set_var("DOMAIN_URL", "https://auth.example.com");
+    std::env::set_var("RETURN_TO_HOSTS", "app.example.com");
+    std::env::set_var("RETURN_TO_PATHS", "/app,/account/");
+  }
+
+  #[test]
+  fn allowed_paths_are_kept_with_their_query_and_fragment() {
+    configure();
+
+    assert_eq!(safe_return_to("/app").as_deref(), Some("/app"));
+    assert_eq!(safe_return_to("/app/settings?tab=1#top").as_deref(), Some("/app/settings?tab=1#top"));
+    assert_eq!(safe_return_to("/account").as_deref(), Some("/account"));
+  }
+
+  #[test]
+  fn path_prefixes_match_whole_segments() {
+    configure();
+
+    assert_eq!(safe_return_to("/application"), None);
+    assert_eq!(safe_return_to("/accounts"), None);
+    assert_eq!(safe_return_to("/"), None);
+  }
+
+  #[test]
+  fn paths_are_checked_as_the_browser_resolves_them() {
+    configure();
+
+    assert_eq!(safe_return_to("/app/../admin"), None);
+    assert_eq!(safe_return_to("/app/%2e%2e/admin"), None);
+    assert_eq!(safe_return_to("/app/./settings").as_deref(), Some("/app/settings"));
+  }
+
+  #[test]
+  fn other_hosts_are_refused() {
+    configure();
+
+    assert_eq!(safe_return_to("//evil.example/app"), None);
+    assert_eq!(safe_return_to("/\\evil.example/app"), None);
+    assert_eq!(safe_return_to("https://evil.example/app"), None);
+    assert_eq!(safe_return_to("https://app.example.com.evil/app"), None);
+    assert_eq!(safe_return_to("https://app.example.com@evil.example/app"), None);
+  }
+
+  #[test]
+  fn our_host_and_allowed_hosts_are_kept() {
+    configure();
+
+    assert_eq!(
+      safe_return_to("https://auth.example.com/account/email").as_deref(),
+      Some("https://auth.example.com/account/email")
+    );
+    assert_eq!(safe_return_to("https://app.example.com/app").as_deref(), Some("https://app.example.com/app"));
+    assert_eq!(safe_return_to("https://app.example.com/admin"), None);
+  }
+
+  #[test]
+  fn anything_else_is_refused() {
+    configure();
+
+    assert_eq!(safe_return_to(""), None);
+    assert_eq!(safe_return_to("app/settings"), None);
+    assert_eq!(safe_return_to("javascript:alert(1)"), None);
+    assert_eq!(safe_return_to("/app\r\nSet-Cookie: a=b"), None);
+  }
+}
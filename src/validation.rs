@@ -0,0 +1,98 @@
+use actix_web::{dev, Error, FromRequest, HttpRequest};
+use futures::future::{FutureExt, LocalBoxFuture, TryFutureExt};
+use serde::de::DeserializeOwned;
+use unicode_normalization::UnicodeNormalization;
+use validator::{Validate, ValidationErrors};
+
+use crate::{
+    errors::{AuthError, FieldError},
+    negotiation::JsonOrForm
+};
+
+
+// Cleans up submitted values before the `#[validate]` rules run
+pub trait Normalize {
+    fn normalize(&mut self) {}
+}
+
+// A JSON or form body that has been normalized and checked against its `#[validate]`
+// rules before the handler runs. Handlers get the per-field errors to show in a form
+// or, with `?`, to return to JSON clients.
+pub struct Validated<T> {
+    data: T,
+    errors: Option<AuthError>,
+}
+
+impl<T> Validated<T> {
+    pub fn into_inner(self) -> Result<T, AuthError> {
+        match self.errors {
+            Some(err) => Err(err),
+            None => Ok(self.data),
+        }
+    }
+
+    // The submitted values even when they're invalid, so a form can be shown again with
+    // what was typed next to the errors
+    pub fn into_parts(self) -> (T, Result<(), AuthError>) {
+        (self.data, self.errors.map_or(Ok(()), Err))
+    }
+}
+
+impl<T: DeserializeOwned + Normalize + Validate + 'static> FromRequest for Validated<T> {
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Error>>;
+    type Config = ();
+
+    fn from_request(req: &HttpRequest, payload: &mut dev::Payload) -> Self::Future {
+        JsonOrForm::<T>::from_request(req, payload)
+            .map_ok(|data| {
+                let mut data = data.into_inner();
+
+                data.normalize();
+
+                let errors = check(&data).err();
+
+                Validated { data, errors }
+            })
+            .boxed_local()
+    }
+}
+
+
+pub fn validate<T: Normalize + Validate>(mut data: T) -> Result<T, AuthError> {
+    data.normalize();
+    check(&data)?;
+
+    Ok(data)
+}
+
+// Trims and NFC normalizes, so "é" typed as one code point or two is the same text.
+// Not for passwords, whose existing hashes were made from the bytes as typed.
+pub fn normalize_text(value: &str) -> String {
+    value.trim().nfc().collect()
+}
+
+
+fn check<T: Validate>(data: &T) -> Result<(), AuthError> {
+    data.validate().map_err(|errors| AuthError::ValidationError(field_errors(&errors)))
+}
+
+fn field_errors(errors: &ValidationErrors) -> Vec<FieldError> {
+    let mut field_errors: Vec<FieldError> = errors
+        .field_errors()
+        .into_iter()
+        .flat_map(|(field, errors)| {
+            errors.iter().map(move |error| FieldError {
+                field: String::from(field),
+                message: error
+                    .message
+                    .as_ref()
+                    .map_or_else(|| format!("{} is invalid", field), |message| message.to_string()),
+            })
+        })
+        .collect();
+
+    // the errors come out of a HashMap
+    field_errors.sort_by(|a, b| a.field.cmp(&b.field));
+    field_errors
+}
@@ -9,12 +9,87 @@ pub fn database_url() -> String {
   var("DATABASE_URL").expect("DATABASE_URL is not set")
 }
 
+// Apply pending migrations when the server starts
+pub fn run_migrations() -> bool {
+  dotenv().ok();
+
+  var("RUN_MIGRATIONS").map_or(false, |value| value == "true" || value == "1")
+}
+
 pub fn secret_key() -> String {
   dotenv().ok();
 
   var("SECRET_KEY").unwrap_or_else(|_| "0123".repeat(8))
 }
 
+// Password hashing keys as "id:secret,id:secret", current key first and retired ones after it.
+// Without it, `SECRET_KEY` is the only key, with the id "default".
+pub fn secret_keys() -> Vec<(String, String)> {
+  dotenv().ok();
+
+  match var("SECRET_KEYS") {
+    Ok(keys) => keys
+      .split(',')
+      .map(|key| {
+        let mut parts = key.trim().splitn(2, ':');
+
+        match (parts.next(), parts.next()) {
+          (Some(id), Some(secret)) if !id.is_empty() && !secret.is_empty() => (id.to_string(), secret.to_string()),
+          _ => panic!("SECRET_KEYS should be a comma separated list of id:secret pairs"),
+        }
+      })
+      .collect(),
+    Err(_) => vec![("default".to_string(), secret_key())],
+  }
+}
+
+// Set APP_ENV=production to refuse insecure defaults
+pub fn is_production() -> bool {
+  dotenv().ok();
+
+  var("APP_ENV").map_or(false, |env| env == "production")
+}
+
+// hex encoded, at least 32 bytes. `auth-admin generate-keys` prints a fresh one.
+pub fn session_key() -> Vec<u8> {
+  dotenv().ok();
+
+  var("SESSION_KEY")
+    .map(|key| hex::decode(key).ok().expect("SESSION_KEY should be hex encoded"))
+    .unwrap_or_else(|_| vec![0; 32])
+}
+
+// Set this to share the session cookie with apps on sibling subdomains
+pub fn session_cookie_domain() -> Option<String> {
+  dotenv().ok();
+
+  var("SESSION_COOKIE_DOMAIN").ok().filter(|domain| !domain.is_empty())
+}
+
+// Hosts, besides our own, that users may be sent back to after signing in
+pub fn return_to_hosts() -> Vec<String> {
+  dotenv().ok();
+
+  var("RETURN_TO_HOSTS")
+    .unwrap_or_default()
+    .split(',')
+    .map(|host| host.trim().to_lowercase())
+    .filter(|host| !host.is_empty())
+    .collect()
+}
+
+// Path prefixes users may be sent back to after signing in. Any path is allowed when this isn't set.
+pub fn return_to_paths() -> Vec<String> {
+  dotenv().ok();
+
+  var("RETURN_TO_PATHS")
+    .unwrap_or_default()
+    .split(',')
+    .map(|path| path.trim().to_string())
+    .filter(|path| !path.is_empty())
+    .collect()
+}
+
 pub fn domain() -> String {
   dotenv().ok();
 
@@ -61,4 +136,369 @@ pub fn smtp_sender_name() -> String {
   dotenv().ok();
 
   var("SMTP_SENDER_NAME").expect("SMTP_SENDER_NAME is not set")
-}
\ No newline at end of file
+}
+
+pub fn email_change_revert_days() -> i64 {
+  dotenv().ok();
+
+  var("EMAIL_CHANGE_REVERT_DAYS")
+    .unwrap_or_else(|_| "7".to_string())
+    .parse::<i64>()
+    .ok()
+    .expect("EMAIL_CHANGE_REVERT_DAYS should be an integer")
+}
+
+// Days a user has to wait between username changes, 0 lets them change it any time
+pub fn username_change_cooldown_days() -> i64 {
+  dotenv().ok();
+
+  var("USERNAME_CHANGE_COOLDOWN_DAYS")
+    .unwrap_or_else(|_| "30".to_string())
+    .parse::<i64>()
+    .ok()
+    .expect("USERNAME_CHANGE_COOLDOWN_DAYS should be an integer")
+}
+
+// Names nobody may take, on top of the built-in ones like "admin"
+pub fn username_reserved() -> Vec<String> {
+  dotenv().ok();
+
+  var("USERNAME_RESERVED")
+    .unwrap_or_default()
+    .split(',')
+    .map(|name| name.trim().to_lowercase())
+    .filter(|name| !name.is_empty())
+    .collect()
+}
+
+
+pub fn account_deletion_grace_hours() -> i64 {
+  dotenv().ok();
+
+  var("ACCOUNT_DELETION_GRACE_HOURS")
+    .unwrap_or_else(|_| "72".to_string())
+    .parse::<i64>()
+    .ok()
+    .expect("ACCOUNT_DELETION_GRACE_HOURS should be an integer")
+}
+
+// "delete" removes the user's rows, "anonymize" keeps them with personal data scrubbed
+pub fn account_deletion_mode() -> String {
+  dotenv().ok();
+
+  var("ACCOUNT_DELETION_MODE").unwrap_or_else(|_| "delete".to_string())
+}
+
+pub fn confirmation_resend_cooldown_secs() -> i64 {
+  dotenv().ok();
+
+  var("CONFIRMATION_RESEND_COOLDOWN_SECS")
+    .unwrap_or_else(|_| "60".to_string())
+    .parse::<i64>()
+    .ok()
+    .expect("CONFIRMATION_RESEND_COOLDOWN_SECS should be an integer")
+}
+
+// 0 disables the scheduler
+pub fn maintenance_interval_secs() -> u64 {
+  dotenv().ok();
+
+  var("MAINTENANCE_INTERVAL_SECS")
+    .unwrap_or_else(|_| "3600".to_string())
+    .parse::<u64>()
+    .ok()
+    .expect("MAINTENANCE_INTERVAL_SECS should be an integer")
+}
+
+// The admin API is disabled when this isn't set
+pub fn admin_token() -> Option<String> {
+  dotenv().ok();
+
+  var("ADMIN_TOKEN").ok().filter(|token| !token.is_empty())
+}
+
+// When set, /metrics requires `Authorization: Bearer <METRICS_TOKEN>`
+pub fn metrics_token() -> Option<String> {
+  dotenv().ok();
+
+  var("METRICS_TOKEN").ok().filter(|token| !token.is_empty())
+}
+
+// When set, /metrics is served on this port instead of the main one
+pub fn metrics_port() -> Option<u16> {
+  dotenv().ok();
+
+  var("METRICS_PORT").ok().map(|port| port.parse::<u16>().ok().expect("METRICS_PORT should be an integer"))
+}
+
+// "json" for one JSON object per line, anything else for human readable logs
+pub fn log_format() -> String {
+  dotenv().ok();
+
+  var("LOG_FORMAT").unwrap_or_else(|_| "text".to_string())
+}
+
+// Where traces are exported when built with the `otlp` feature
+pub fn otlp_endpoint() -> Option<String> {
+  dotenv().ok();
+
+  var("OTEL_EXPORTER_OTLP_ENDPOINT").ok().filter(|endpoint| !endpoint.is_empty())
+}
+
+pub fn redis_address() -> String {
+  dotenv().ok();
+
+  var("REDIS_ADDRESS").unwrap_or_else(|_| "127.0.0.1:6379".to_string())
+}
+
+// Include the SMTP server in /readyz
+pub fn health_check_smtp() -> bool {
+  dotenv().ok();
+
+  var("HEALTH_CHECK_SMTP").map_or(false, |value| value == "true" || value == "1")
+}
+
+pub fn health_check_timeout_ms() -> u64 {
+  dotenv().ok();
+
+  var("HEALTH_CHECK_TIMEOUT_MS")
+    .unwrap_or_else(|_| "2000".to_string())
+    .parse::<u64>()
+    .ok()
+    .expect("HEALTH_CHECK_TIMEOUT_MS should be an integer")
+}
+
+// How long /readyz reports not ready before the server stops accepting connections
+pub fn shutdown_drain_secs() -> u64 {
+  dotenv().ok();
+
+  var("SHUTDOWN_DRAIN_SECS")
+    .unwrap_or_else(|_| "5".to_string())
+    .parse::<u64>()
+    .ok()
+    .expect("SHUTDOWN_DRAIN_SECS should be an integer")
+}
+
+// Threads hashing and verifying passwords, defaults to the number of CPUs
+pub fn credential_workers() -> usize {
+  dotenv().ok();
+
+  var("CREDENTIAL_WORKERS")
+    .ok()
+    .map(|workers| workers.parse::<usize>().ok().expect("CREDENTIAL_WORKERS should be an integer"))
+    .unwrap_or_else(num_cpus::get)
+}
+
+// Credential jobs that may wait for a worker before requests get a 429
+pub fn credential_queue_size() -> usize {
+  dotenv().ok();
+
+  var("CREDENTIAL_QUEUE_SIZE")
+    .unwrap_or_else(|_| "64".to_string())
+    .parse::<usize>()
+    .ok()
+    .expect("CREDENTIAL_QUEUE_SIZE should be an integer")
+}
+
+pub fn database_pool_size() -> u32 {
+  dotenv().ok();
+
+  var("DATABASE_POOL_SIZE")
+    .unwrap_or_else(|_| "10".to_string())
+    .parse::<u32>()
+    .ok()
+    .expect("DATABASE_POOL_SIZE should be an integer")
+}
+
+// How long to wait for a free connection before answering 503
+pub fn database_connect_timeout_ms() -> u64 {
+  dotenv().ok();
+
+  var("DATABASE_CONNECT_TIMEOUT_MS")
+    .unwrap_or_else(|_| "5000".to_string())
+    .parse::<u64>()
+    .ok()
+    .expect("DATABASE_CONNECT_TIMEOUT_MS should be an integer")
+}
+
+// 0 leaves statements without a timeout
+pub fn database_statement_timeout_ms() -> u64 {
+  dotenv().ok();
+
+  var("DATABASE_STATEMENT_TIMEOUT_MS")
+    .unwrap_or_else(|_| "10000".to_string())
+    .parse::<u64>()
+    .ok()
+    .expect("DATABASE_STATEMENT_TIMEOUT_MS should be an integer")
+}
+
+// argon2d, argon2i or argon2id
+pub fn argon2_variant() -> argonautica::config::Variant {
+  dotenv().ok();
+
+  var("ARGON2_VARIANT")
+    .unwrap_or_else(|_| "argon2id".to_string())
+    .parse()
+    .ok()
+    .expect("ARGON2_VARIANT should be one of argon2d, argon2i or argon2id")
+}
+
+pub fn argon2_memory_kib() -> u32 {
+  dotenv().ok();
+
+  var("ARGON2_MEMORY_KIB")
+    .unwrap_or_else(|_| "4096".to_string())
+    .parse::<u32>()
+    .ok()
+    .expect("ARGON2_MEMORY_KIB should be an integer")
+}
+
+pub fn argon2_iterations() -> u32 {
+  dotenv().ok();
+
+  var("ARGON2_ITERATIONS")
+    .unwrap_or_else(|_| "192".to_string())
+    .parse::<u32>()
+    .ok()
+    .expect("ARGON2_ITERATIONS should be an integer")
+}
+
+// Part of the hash parameters, so it has to be the same on every replica whatever its CPU count
+pub fn argon2_lanes() -> u32 {
+  dotenv().ok();
+
+  var("ARGON2_LANES")
+    .unwrap_or_else(|_| "8".to_string())
+    .parse::<u32>()
+    .ok()
+    .expect("ARGON2_LANES should be an integer")
+}
+
+// The hash parameters from the Firebase console, for users imported from Firebase Auth
+pub fn firebase_signer_key() -> Option<String> {
+  dotenv().ok();
+
+  var("FIREBASE_SIGNER_KEY").ok().filter(|key| !key.is_empty())
+}
+
+pub fn firebase_salt_separator() -> String {
+  dotenv().ok();
+
+  var("FIREBASE_SALT_SEPARATOR").unwrap_or_else(|_| "Bw==".to_string())
+}
+
+pub fn firebase_rounds() -> u32 {
+  dotenv().ok();
+
+  var("FIREBASE_ROUNDS")
+    .unwrap_or_else(|_| "8".to_string())
+    .parse::<u32>()
+    .ok()
+    .expect("FIREBASE_ROUNDS should be an integer")
+}
+
+pub fn firebase_mem_cost() -> u8 {
+  dotenv().ok();
+
+  var("FIREBASE_MEM_COST")
+    .unwrap_or_else(|_| "14".to_string())
+    .parse::<u8>()
+    .ok()
+    .expect("FIREBASE_MEM_COST should be an integer")
+}
+
+pub fn password_min_length() -> usize {
+  dotenv().ok();
+
+  var("PASSWORD_MIN_LENGTH")
+    .unwrap_or_else(|_| "8".to_string())
+    .parse::<usize>()
+    .ok()
+    .expect("PASSWORD_MIN_LENGTH should be an integer")
+}
+
+pub fn password_max_length() -> usize {
+  dotenv().ok();
+
+  var("PASSWORD_MAX_LENGTH")
+    .unwrap_or_else(|_| "128".to_string())
+    .parse::<usize>()
+    .ok()
+    .expect("PASSWORD_MAX_LENGTH should be an integer")
+}
+
+// zxcvbn score from 0 (guessable) to 4 (very unguessable), 0 turns the check off
+pub fn password_min_score() -> u8 {
+  dotenv().ok();
+
+  var("PASSWORD_MIN_SCORE")
+    .unwrap_or_else(|_| "2".to_string())
+    .parse::<u8>()
+    .ok()
+    .expect("PASSWORD_MIN_SCORE should be an integer from 0 to 4")
+}
+
+pub fn password_forbid_email() -> bool {
+  dotenv().ok();
+
+  var("PASSWORD_FORBID_EMAIL").map_or(true, |value| value == "true" || value == "1")
+}
+
+// A directory of Pwned Passwords range files to reject breached passwords with
+pub fn password_breach_corpus_dir() -> Option<String> {
+  dotenv().ok();
+
+  var("PASSWORD_BREACH_CORPUS_DIR").ok().filter(|dir| !dir.is_empty())
+}
+
+// How many previous passwords can't be reused, 0 allows reuse
+pub fn password_history_size() -> i64 {
+  dotenv().ok();
+
+  var("PASSWORD_HISTORY_SIZE")
+    .unwrap_or_else(|_| "0".to_string())
+    .parse::<i64>()
+    .ok()
+    .expect("PASSWORD_HISTORY_SIZE should be an integer")
+}
+
+// Days after which a password has to be changed, 0 lets passwords live forever
+pub fn password_max_age_days() -> i64 {
+  dotenv().ok();
+
+  var("PASSWORD_MAX_AGE_DAYS")
+    .unwrap_or_else(|_| "0".to_string())
+    .parse::<i64>()
+    .ok()
+    .expect("PASSWORD_MAX_AGE_DAYS should be an integer")
+}
+
+// Where the API docs page loads Redoc from, for hosting it alongside the service
+pub fn redoc_script_url() -> String {
+  dotenv().ok();
+
+  var("REDOC_SCRIPT_URL")
+    .unwrap_or_else(|_| "https://cdn.jsdelivr.net/npm/redoc@2.0.0-rc.48/bundles/redoc.standalone.js".to_string())
+}
+
+// For a Redis server that requires AUTH, used by /readyz
+pub fn redis_password() -> Option<String> {
+  dotenv().ok();
+
+  var("REDIS_PASSWORD").ok().filter(|password| !password.is_empty())
+}
+
+// Whether "Alice@example.com" and "alice@example.com" are the same account. Nearly every
+// provider ignores case in the local part, though the standard leaves it to them.
+pub fn email_fold_local_case() -> bool {
+  dotenv().ok();
+
+  var("EMAIL_FOLD_LOCAL_CASE").map_or(true, |value| value == "true" || value == "1")
+}
+
+// Whether "a.lice@gmail.com" and "alice@gmail.com" are the same account, as Gmail delivers them to one inbox
+pub fn email_ignore_gmail_dots() -> bool {
+  dotenv().ok();
+
+  var("EMAIL_IGNORE_GMAIL_DOTS").map_or(false, |value| value == "true" || value == "1")
+}
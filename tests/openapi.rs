@@ -0,0 +1,225 @@
+// Contract tests between /api/v1/openapi.json and the handlers and types behind it.
+// None of them need a database or Redis: requests are turned away before a handler
+// touches the store, and models are built in memory.
+
+use std::collections::BTreeSet;
+
+use actix_session::CookieSession;
+use actix_web::{
+    http::{header::CONTENT_TYPE, Method, StatusCode},
+    test,
+    App
+};
+use auth_service::{
+    account_handler::DeleteData,
+    auth_handler::AuthData,
+    email_handler::EmailData,
+    errors::{AuthError, FieldError},
+    models::{AccountDeletion, Pool, SessionUser},
+    openapi::{spec, API_PREFIX},
+    password_handler::{ChangePasswordData, PasswordData},
+    register_handler::RegisterData,
+    reset_handler::ResetPasswordData,
+    service::ROUTES,
+    username_handler::UsernameData,
+    AuthService
+};
+use diesel::{r2d2::{self, ConnectionManager}, PgConnection};
+use serde::Serialize;
+use serde_json::{json, Map, Value};
+use uuid::Uuid;
+
+#[test]
+fn spec_is_openapi_3_with_resolvable_references() {
+    let spec = spec();
+
+    assert!(spec["openapi"].as_str().unwrap().starts_with("3.0."));
+
+    let mut ids = BTreeSet::new();
+
+    for (path, method, operation) in operations(&spec) {
+        assert!(operation["responses"].as_object().map_or(false, |responses| !responses.is_empty()), "{} {}", method, path);
+        assert!(ids.insert(operation["operationId"].as_str().unwrap().to_string()), "duplicate id at {} {}", method, path);
+    }
+
+    for reference in references(&spec) {
+        assert!(component(&spec, &reference).is_object(), "{} doesn't resolve", reference);
+    }
+}
+
+#[actix_rt::test]
+async fn every_documented_operation_is_routed() {
+    let auth = service();
+    let mut app = test::init_service(
+        App::new()
+            .wrap(CookieSession::signed(&[0; 32]).secure(false))
+            .configure(|cfg| auth.configure(cfg))
+    ).await;
+
+    for (path, method, operation) in operations(&spec()) {
+        let uri = format!("{}{}", API_PREFIX, path.replace("{path_id}", &Uuid::new_v4().to_string()));
+        let mut req = test::TestRequest::default().method(method.to_uppercase().parse::<Method>().unwrap()).uri(&uri);
+
+        // a malformed body is rejected before the handler runs
+        if operation.get("requestBody").is_some() {
+            req = req.header(CONTENT_TYPE, "application/json").set_payload("{");
+        }
+
+        let status = test::call_service(&mut app, req.to_request()).await.status();
+
+        assert_ne!(status, StatusCode::NOT_FOUND, "{} {} isn't routed", method, path);
+        assert_ne!(status, StatusCode::METHOD_NOT_ALLOWED, "{} {} isn't routed", method, path);
+    }
+}
+
+#[test]
+fn every_documented_operation_has_a_metrics_label() {
+    for (path, method, _) in operations(&spec()) {
+        let route = format!("{}{}", API_PREFIX, path);
+
+        assert!(ROUTES.contains(&route.as_str()), "{} {} is missing from service::ROUTES", method, path);
+    }
+}
+
+#[actix_rt::test]
+async fn spec_is_served() {
+    let auth = service();
+    let mut app = test::init_service(App::new().configure(|cfg| auth.configure(cfg))).await;
+
+    let req = test::TestRequest::get().uri(&format!("{}/openapi.json", API_PREFIX)).to_request();
+    let served: Value = test::read_response_json(&mut app, req).await;
+
+    assert_eq!(served, spec());
+
+    let req = test::TestRequest::get().uri(&format!("{}/docs", API_PREFIX)).to_request();
+    let res = test::call_service(&mut app, req).await;
+
+    assert_eq!(res.status(), StatusCode::OK);
+}
+
+#[test]
+fn request_schemas_match_the_types_handlers_read() {
+    let spec = spec();
+
+    for (path, method, operation) in operations(&spec) {
+        let reference = match operation["requestBody"]["content"]["application/json"]["schema"]["$ref"].as_str() {
+            Some(reference) => reference,
+            None => continue,
+        };
+        let name = reference.rsplit('/').next().unwrap();
+        let schema = component(&spec, reference);
+        let required = required(schema);
+        let body: Map<String, Value> = properties(schema)
+            .into_iter()
+            .map(|property| (property, Value::String(String::from("value"))))
+            .collect();
+
+        assert!(accepts(name, Value::Object(body.clone())), "{} {} rejects its documented body", method, path);
+
+        for property in &required {
+            let mut body = body.clone();
+
+            body.remove(property);
+
+            assert!(!accepts(name, Value::Object(body)), "{} {} doesn't need {}", method, path, property);
+        }
+    }
+}
+
+#[test]
+fn response_schemas_match_the_types_handlers_write() {
+    let spec = spec();
+    let user = SessionUser {
+        id: Uuid::new_v4(),
+        email: String::from("user@example.com"),
+        roles: vec![],
+        username: Some(String::from("user")),
+    };
+    let deletion = AccountDeletion::from(&user, 24);
+    let problem = AuthError::ValidationError(vec![FieldError {
+        field: String::from("password"),
+        message: String::from("Password is too short"),
+    }])
+    .problem(Some(String::from("request-id")));
+
+    assert_matches(&spec, "SessionUser", &user);
+    assert_matches(&spec, "AccountDeletion", &deletion);
+    assert_matches(&spec, "Problem", &problem);
+    assert_matches(&spec, "Problem", &AuthError::PasswordExpired(String::from("/me/password")).problem(None));
+}
+
+
+fn service() -> AuthService {
+    // never connected to, see the note at the top
+    let pool: Pool = r2d2::Pool::builder()
+        .build_unchecked(ConnectionManager::<PgConnection>::new("postgres://localhost/unused"));
+
+    AuthService::builder().store(pool).build()
+}
+
+fn operations(spec: &Value) -> Vec<(String, String, Value)> {
+    spec["paths"]
+        .as_object()
+        .unwrap()
+        .iter()
+        .flat_map(|(path, methods)| {
+            methods
+                .as_object()
+                .unwrap()
+                .iter()
+                .map(move |(method, operation)| (path.clone(), method.clone(), operation.clone()))
+        })
+        .collect()
+}
+
+fn references(value: &Value) -> Vec<String> {
+    match value {
+        Value::Object(map) => map
+            .iter()
+            .flat_map(|(key, value)| match (key.as_str(), value) {
+                ("$ref", Value::String(reference)) => vec![reference.clone()],
+                _ => references(value),
+            })
+            .collect(),
+        Value::Array(values) => values.iter().flat_map(references).collect(),
+        _ => vec![],
+    }
+}
+
+// "#/components/schemas/AuthData"
+fn component<'a>(spec: &'a Value, reference: &str) -> &'a Value {
+    spec.pointer(reference.trim_start_matches('#')).unwrap_or(&Value::Null)
+}
+
+fn properties(schema: &Value) -> BTreeSet<String> {
+    schema["properties"].as_object().map_or_else(BTreeSet::new, |properties| properties.keys().cloned().collect())
+}
+
+fn required(schema: &Value) -> BTreeSet<String> {
+    schema["required"]
+        .as_array()
+        .map_or_else(BTreeSet::new, |required| required.iter().filter_map(Value::as_str).map(String::from).collect())
+}
+
+fn accepts(name: &str, body: Value) -> bool {
+    match name {
+        "AuthData" => serde_json::from_value::<AuthData>(body).is_ok(),
+        "ChangePasswordData" => serde_json::from_value::<ChangePasswordData>(body).is_ok(),
+        "DeleteData" => serde_json::from_value::<DeleteData>(body).is_ok(),
+        "EmailData" => serde_json::from_value::<EmailData>(body).is_ok(),
+        "PasswordData" => serde_json::from_value::<PasswordData>(body).is_ok(),
+        "RegisterData" => serde_json::from_value::<RegisterData>(body).is_ok(),
+        "ResetPasswordData" => serde_json::from_value::<ResetPasswordData>(body).is_ok(),
+        "UsernameData" => serde_json::from_value::<UsernameData>(body).is_ok(),
+        other => panic!("No request type is known for the {} schema", other),
+    }
+}
+
+// Every field written is documented and every required one is written
+fn assert_matches<T: Serialize>(spec: &Value, name: &str, value: &T) {
+    let schema = component(spec, &format!("#/components/schemas/{}", name));
+    let written: BTreeSet<String> = json!(value).as_object().unwrap().keys().cloned().collect();
+
+    assert!(written.is_subset(&properties(schema)), "{} writes undocumented fields", name);
+    assert!(required(schema).is_subset(&written), "{} leaves out required fields", name);
+}